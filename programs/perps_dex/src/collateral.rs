@@ -1,73 +1,433 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Token, Transfer};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 
-use crate::{errors::ErrorCode, state::{MarginAccount, Market}};
+use crate::{
+    errors::ErrorCode,
+    events::{CollateralDeposited, CollateralLiquidated, CollateralWithdrawn},
+    margin::check_withdrawal,
+    state::{
+        CollateralConfig, CollateralRegistry, MarginAccount, BPS, COLLATERAL_LIQUIDATION_BONUS_BPS,
+    },
+    utils::{get_oracle_price, received_amount, transfer_collateral},
+};
 
 #[derive(Accounts)]
 pub struct DepositCollateral<'info> {
-    #[account(mut, has_one = authority)]
-    pub market: Account<'info, Market>,
-    pub authority: Signer<'info>,
-    #[account(mut, seeds = [b"margin", market.key().as_ref(), user.key().as_ref()], bump)]
+    #[account(seeds = [b"collateral_registry"], bump = registry.bump)]
+    pub registry: Account<'info, CollateralRegistry>,
+    #[account(mut, seeds = [b"margin", user.key().as_ref()], bump)]
     pub margin: Account<'info, MarginAccount>,
     pub user: Signer<'info>,
+    #[account(address = registry.quote_mint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
     #[account(mut, constraint = user_collateral.owner == user.key())]
-    pub user_collateral: Account<'info, anchor_spl::token::TokenAccount>,
-    #[account(mut, constraint = market_vault.owner == market.key())]
-    pub market_vault: Account<'info, anchor_spl::token::TokenAccount>,
-    pub token_program: Program<'info, Token>,
+    pub user_collateral: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, address = registry.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
 pub struct WithdrawCollateral<'info> {
-    #[account(mut, has_one = authority)]
-    pub market: Account<'info, Market>,
-    pub authority: Signer<'info>,
-    #[account(mut, seeds = [b"margin", market.key().as_ref(), user.key().as_ref()], bump)]
+    #[account(seeds = [b"collateral_registry"], bump = registry.bump)]
+    pub registry: Account<'info, CollateralRegistry>,
+    #[account(mut, seeds = [b"margin", user.key().as_ref()], bump)]
     pub margin: Account<'info, MarginAccount>,
     pub user: Signer<'info>,
-    #[account(mut, constraint = market_vault.owner == market.key())]
-    pub market_vault: Account<'info, anchor_spl::token::TokenAccount>,
+    #[account(address = registry.quote_mint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = registry.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
     #[account(mut, constraint = user_collateral.owner == user.key())]
-    pub user_collateral: Account<'info, anchor_spl::token::TokenAccount>,
-    pub token_program: Program<'info, anchor_spl::token::Token>,
+    pub user_collateral: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct LiquidateCollateral<'info> {
+    #[account(seeds = [b"collateral_registry"], bump = registry.bump)]
+    pub registry: Account<'info, CollateralRegistry>,
+    #[account(mut, seeds = [b"margin", margin.owner.as_ref()], bump = margin.bump)]
+    pub margin: Account<'info, MarginAccount>,
+    #[account(mut, seeds = [b"collateral", config.mint.as_ref()], bump = config.bump)]
+    pub config: Account<'info, CollateralConfig>,
+    #[account(address = config.mint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = config.vault)]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+    /// CHECK: price feed of the seized mint
+    #[account(address = config.oracle_pyth)]
+    pub oracle_pyth: AccountInfo<'info>,
+    /// CHECK: price feed of the seized mint
+    #[account(address = config.oracle_switchboard)]
+    pub oracle_switchboard: AccountInfo<'info>,
+    #[account(address = registry.quote_mint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = registry.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    pub liquidator: Signer<'info>,
+    /// Pays the debt
+    #[account(mut, constraint = liquidator_quote.owner == liquidator.key())]
+    pub liquidator_quote: InterfaceAccount<'info, TokenAccount>,
+    /// Receives the seized collateral
+    #[account(mut, constraint = liquidator_collateral.mint == config.mint)]
+    pub liquidator_collateral: InterfaceAccount<'info, TokenAccount>,
+    /// Token program of the seized mint
+    pub token_program: Interface<'info, TokenInterface>,
+    /// Token program of the quote mint
+    pub quote_token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeCollateralRegistry<'info> {
+    #[account(
+        init,
+        payer = authority,
+        seeds = [b"collateral_registry"],
+        bump,
+        space = 8 + CollateralRegistry::INIT_SPACE,
+    )]
+    pub registry: Account<'info, CollateralRegistry>,
+    #[account(mint::token_program = token_program)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = authority,
+        seeds = [b"quote_vault"],
+        bump,
+        token::mint = quote_mint,
+        token::authority = registry,
+        token::token_program = token_program,
+    )]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    /// Only the program's upgrade authority can set up the registry
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::PerpsDex>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(authority.key()) @ ErrorCode::Unauthorized)]
+    pub program_data: Account<'info, ProgramData>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ListCollateral<'info> {
+    #[account(seeds = [b"collateral_registry"], bump = registry.bump, has_one = authority)]
+    pub registry: Account<'info, CollateralRegistry>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+    #[account(
+        mint::token_program = token_program,
+        constraint = mint.key() != registry.quote_mint @ ErrorCode::InvalidQuoteMint,
+    )]
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(
+        init,
+        payer = authority,
+        seeds = [b"collateral", mint.key().as_ref()],
+        bump,
+        space = 8 + CollateralConfig::INIT_SPACE,
+    )]
+    pub config: Account<'info, CollateralConfig>,
+    #[account(
+        init,
+        payer = authority,
+        seeds = [b"collateral_vault", mint.key().as_ref()],
+        bump,
+        token::mint = mint,
+        token::authority = config,
+        token::token_program = token_program,
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+    /// CHECK: price feed of the mint, read when valuing balances
+    pub oracle_pyth: AccountInfo<'info>,
+    /// CHECK: price feed of the mint, read when valuing balances
+    pub oracle_switchboard: AccountInfo<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateCollateral<'info> {
+    #[account(seeds = [b"collateral_registry"], bump = registry.bump, has_one = authority)]
+    pub registry: Account<'info, CollateralRegistry>,
+    pub authority: Signer<'info>,
+    #[account(mut, seeds = [b"collateral", config.mint.as_ref()], bump = config.bump)]
+    pub config: Account<'info, CollateralConfig>,
+}
+
+#[derive(Accounts)]
+pub struct TransferListedCollateral<'info> {
+    #[account(mut, seeds = [b"margin", user.key().as_ref()], bump)]
+    pub margin: Account<'info, MarginAccount>,
+    pub user: Signer<'info>,
+    #[account(mut, seeds = [b"collateral", config.mint.as_ref()], bump = config.bump)]
+    pub config: Account<'info, CollateralConfig>,
+    #[account(address = config.mint)]
+    pub mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, address = config.vault)]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(
+        mut,
+        constraint = user_collateral.owner == user.key(),
+        constraint = user_collateral.mint == config.mint,
+    )]
+    pub user_collateral: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+pub fn initialize_collateral_registry(ctx: Context<InitializeCollateralRegistry>) -> Result<()> {
+    let registry = &mut ctx.accounts.registry;
+    registry.authority = ctx.accounts.authority.key();
+    registry.quote_mint = ctx.accounts.quote_mint.key();
+    registry.quote_vault = ctx.accounts.quote_vault.key();
+    registry.quote_decimals = ctx.accounts.quote_mint.decimals;
+    registry.bump = ctx.bumps.registry;
+    Ok(())
+}
+
+/// List `mint` as margin collateral. Its balances count towards equity at
+/// `weight_bps` of their oracle value.
+pub fn list_collateral(
+    ctx: Context<ListCollateral>,
+    weight_bps: u16,
+    deposit_cap: u64,
+    oracle_max_age: u64,
+) -> Result<()> {
+    require!(
+        weight_bps as u128 <= BPS,
+        ErrorCode::InvalidCollateralWeight
+    );
+    let config = &mut ctx.accounts.config;
+    config.mint = ctx.accounts.mint.key();
+    config.vault = ctx.accounts.vault.key();
+    config.oracle_pyth = ctx.accounts.oracle_pyth.key();
+    config.oracle_switchboard = ctx.accounts.oracle_switchboard.key();
+    config.decimals = ctx.accounts.mint.decimals;
+    config.quote_decimals = ctx.accounts.registry.quote_decimals;
+    config.weight_bps = weight_bps;
+    config.deposit_cap = deposit_cap;
+    config.total_deposits = 0;
+    config.oracle_max_age = oracle_max_age;
+    config.bump = ctx.bumps.config;
+    Ok(())
+}
+
+/// Change the weight or deposit cap of a listed mint. Lowering the cap
+/// below current deposits only blocks new ones.
+pub fn update_collateral(
+    ctx: Context<UpdateCollateral>,
+    weight_bps: u16,
+    deposit_cap: u64,
+) -> Result<()> {
+    require!(
+        weight_bps as u128 <= BPS,
+        ErrorCode::InvalidCollateralWeight
+    );
+    let config = &mut ctx.accounts.config;
+    config.weight_bps = weight_bps;
+    config.deposit_cap = deposit_cap;
+    Ok(())
+}
+
+/// Deposit listed collateral. Only what arrives in the vault is credited
+/// and counted against the deposit cap.
+pub fn deposit_listed_collateral(
+    ctx: Context<TransferListedCollateral>,
+    amount: u64,
+) -> Result<()> {
+    require!(amount > 0, ErrorCode::InvalidAmount);
+
+    let before = ctx.accounts.vault.amount;
+    transfer_collateral(
+        &ctx.accounts.token_program,
+        &ctx.accounts.user_collateral,
+        &ctx.accounts.vault,
+        ctx.accounts.user.to_account_info(),
+        &ctx.accounts.mint,
+        amount,
+        &[],
+    )?;
+    let received = received_amount(&mut ctx.accounts.vault, before)?;
+
+    let config = &mut ctx.accounts.config;
+    config.total_deposits = config
+        .total_deposits
+        .checked_add(received)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    require!(
+        config.total_deposits <= config.deposit_cap,
+        ErrorCode::DepositCapExceeded
+    );
+    let margin = &mut ctx.accounts.margin;
+    margin.deposit_listed(config.mint, received)?;
+
+    emit!(CollateralDeposited {
+        owner: margin.owner,
+        mint: config.mint,
+        amount: received,
+        balance: margin.listed_balance(&config.mint),
+    });
+    Ok(())
+}
+
+/// Withdraw listed collateral. The account's other markets and the configs
+/// of the mints it still holds are passed in remaining accounts.
+pub fn withdraw_listed_collateral(
+    ctx: Context<TransferListedCollateral>,
+    amount: u64,
+) -> Result<()> {
+    require!(amount > 0, ErrorCode::InvalidAmount);
+    let mint = ctx.accounts.config.mint;
+    let margin = &mut ctx.accounts.margin;
+    require!(margin.debt == 0, ErrorCode::OutstandingDebt);
+    margin.withdraw_listed(&mint, amount)?;
+    check_withdrawal(margin, ctx.remaining_accounts)?;
+
+    let config = &mut ctx.accounts.config;
+    config.total_deposits = config.total_deposits.saturating_sub(amount);
+
+    let seeds: &[&[u8]] = &[b"collateral", mint.as_ref(), &[config.bump]];
+    transfer_collateral(
+        &ctx.accounts.token_program,
+        &ctx.accounts.vault,
+        &ctx.accounts.user_collateral,
+        config.to_account_info(),
+        &ctx.accounts.mint,
+        amount,
+        &[seeds],
+    )?;
+
+    emit!(CollateralWithdrawn {
+        owner: margin.owner,
+        mint,
+        amount,
+        balance: margin.listed_balance(&mint),
+    });
+    Ok(())
 }
 
+/// Deposit quote collateral. Only what arrives in the vault is credited, so
+/// transfer-fee mints never credit more than the vault holds.
 pub fn deposit_collateral(ctx: Context<DepositCollateral>, amount: u64) -> Result<()> {
     require!(amount > 0, ErrorCode::InvalidAmount);
 
-    let cpi_accounts = Transfer {
-        from: ctx.accounts.user_collateral.to_account_info(),
-        to: ctx.accounts.market_vault.to_account_info(),
-        authority: ctx.accounts.user.to_account_info(),
-    };
-    let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-    token::transfer(cpi_ctx, amount)?;
+    let before = ctx.accounts.quote_vault.amount;
+    transfer_collateral(
+        &ctx.accounts.token_program,
+        &ctx.accounts.user_collateral,
+        &ctx.accounts.quote_vault,
+        ctx.accounts.user.to_account_info(),
+        &ctx.accounts.quote_mint,
+        amount,
+        &[],
+    )?;
+    let received = received_amount(&mut ctx.accounts.quote_vault, before)?;
 
     let margin = &mut ctx.accounts.margin;
-    margin.collateral = margin.collateral.saturating_add(amount);
+    margin.credit(received)?;
 
+    emit!(CollateralDeposited {
+        owner: margin.owner,
+        mint: ctx.accounts.quote_mint.key(),
+        amount: received,
+        balance: margin.collateral,
+    });
     Ok(())
 }
 
+/// Withdraw quote collateral. Every market the account trades and the
+/// configs of the listed mints it holds are passed in remaining accounts.
 pub fn withdraw_collateral(ctx: Context<WithdrawCollateral>, amount: u64) -> Result<()> {
     require!(amount > 0, ErrorCode::InvalidAmount);
 
     let margin = &mut ctx.accounts.margin;
-    let after = margin
+    require!(margin.debt == 0, ErrorCode::OutstandingDebt);
+    margin.collateral = margin
         .collateral
         .checked_sub(amount)
         .ok_or(error!(ErrorCode::InsufficientCollateral))?;
+    check_withdrawal(margin, ctx.remaining_accounts)?;
+
+    let seeds: &[&[u8]] = &[b"collateral_registry", &[ctx.accounts.registry.bump]];
+    transfer_collateral(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_vault,
+        &ctx.accounts.user_collateral,
+        ctx.accounts.registry.to_account_info(),
+        &ctx.accounts.quote_mint,
+        amount,
+        &[seeds],
+    )?;
 
-    margin.collateral = after;
+    emit!(CollateralWithdrawn {
+        owner: margin.owner,
+        mint: ctx.accounts.quote_mint.key(),
+        amount,
+        balance: margin.collateral,
+    });
+    Ok(())
+}
+
+/// Repay up to `amount` of an account's quote debt and take its listed
+/// collateral in `config.mint` for it, at the oracle value plus
+/// `COLLATERAL_LIQUIDATION_BONUS_BPS`. What arrives beyond the collateral
+/// the account holds is credited to it.
+pub fn liquidate_collateral(ctx: Context<LiquidateCollateral>, amount: u64) -> Result<()> {
+    let debt = ctx.accounts.margin.debt;
+    require!(debt > 0, ErrorCode::NoDebt);
+    let amount = amount.min(debt);
+    require!(amount > 0, ErrorCode::InvalidAmount);
+    let (price, expo) = get_oracle_price(
+        &mut ctx.accounts.oracle_pyth.clone(),
+        &mut ctx.accounts.oracle_switchboard.clone(),
+        ctx.accounts.config.oracle_max_age,
+        5,
+        3,
+    )?;
+
+    let before = ctx.accounts.quote_vault.amount;
+    transfer_collateral(
+        &ctx.accounts.quote_token_program,
+        &ctx.accounts.liquidator_quote,
+        &ctx.accounts.quote_vault,
+        ctx.accounts.liquidator.to_account_info(),
+        &ctx.accounts.quote_mint,
+        amount,
+        &[],
+    )?;
+    let received = received_amount(&mut ctx.accounts.quote_vault, before)?;
+
+    let config = &mut ctx.accounts.config;
+    let mint = config.mint;
+    let margin = &mut ctx.accounts.margin;
+    let seized = config
+        .amount_for_value(received, price, expo, COLLATERAL_LIQUIDATION_BONUS_BPS)?
+        .min(margin.listed_balance(&mint));
+    require!(seized > 0, ErrorCode::InsufficientCollateral);
+    margin.credit(received)?;
+    margin.withdraw_listed(&mint, seized)?;
+    config.total_deposits = config.total_deposits.saturating_sub(seized);
 
-    let cpi_accounts = Transfer {
-        from: ctx.accounts.market_vault.to_account_info(),
-        to: ctx.accounts.user_collateral.to_account_info(),
-        authority: ctx.accounts.market.to_account_info(),
-    };
-    let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-    token::transfer(cpi_ctx, amount)?;
+    let seeds: &[&[u8]] = &[b"collateral", mint.as_ref(), &[config.bump]];
+    transfer_collateral(
+        &ctx.accounts.token_program,
+        &ctx.accounts.vault,
+        &ctx.accounts.liquidator_collateral,
+        config.to_account_info(),
+        &ctx.accounts.mint,
+        seized,
+        &[seeds],
+    )?;
 
+    emit!(CollateralLiquidated {
+        owner: margin.owner,
+        liquidator: ctx.accounts.liquidator.key(),
+        mint,
+        repaid: received,
+        seized,
+        debt_after: margin.debt,
+    });
     Ok(())
 }
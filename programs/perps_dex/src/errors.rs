@@ -51,4 +51,74 @@ pub enum ErrorCode {
     #[msg("Invalid capacity for orderbook")]
     InvalidOrderbookCapacity,
     OrderbookFull,
+    #[msg("Order not found")]
+    OrderNotFound,
+    #[msg("Post-only order would cross the book")]
+    PostOnlyWouldCross,
+    #[msg("Fill-or-kill order could not be filled completely")]
+    FillOrKillNotFilled,
+    #[msg("Price is not a multiple of the tick size")]
+    InvalidTickSize,
+    #[msg("Quantity is not a multiple of the lot size")]
+    InvalidLotSize,
+    #[msg("Invalid market parameters")]
+    InvalidMarketParams,
+    #[msg("Order key already exists in the book")]
+    DuplicateOrderKey,
+    #[msg("Margin account holds the maximum number of positions")]
+    TooManyPositions,
+    #[msg("Position leg does not match the account's position mode or order side")]
+    InvalidPositionLeg,
+    #[msg("Closing order exceeds the open size of the position leg")]
+    ReduceOnlyExceeded,
+    #[msg("Position mode can only change while flat with no resting orders")]
+    PositionModeLocked,
+    #[msg("Oracle accounts are required while positions are open")]
+    MissingOracle,
+    #[msg("Margin type can only change while flat with no resting orders")]
+    MarginTypeLocked,
+    #[msg("Account is not in isolated margin mode")]
+    NotIsolatedMargin,
+    #[msg("Position not found")]
+    PositionNotFound,
+    #[msg("A market the account trades was not passed in remaining accounts")]
+    MissingPortfolioMarket,
+    #[msg("Margin account holds the maximum number of collateral mints")]
+    TooManyCollaterals,
+    #[msg("Collateral weight must be at most 10000 bps")]
+    InvalidCollateralWeight,
+    #[msg("Deposit exceeds the collateral's deposit cap")]
+    DepositCapExceeded,
+    #[msg("A collateral mint the account holds was not passed in remaining accounts")]
+    MissingCollateralPrice,
+    #[msg("Margin account still holds collateral, positions or open orders")]
+    MarginAccountNotEmpty,
+    #[msg("Orderbook side still has resting orders")]
+    OrderbookNotEmpty,
+    #[msg("Event queue still has unconsumed events")]
+    EventQueueNotEmpty,
+    #[msg("Market already has an event queue")]
+    EventQueueAlreadyInitialized,
+    #[msg("Invalid event queue capacity")]
+    InvalidEventQueueCapacity,
+    #[msg("Event queue is full; consume events before trading further")]
+    EventQueueFull,
+    #[msg("Mint is not the registry's quote mint")]
+    InvalidQuoteMint,
+    #[msg("An account was passed twice in remaining accounts")]
+    DuplicatePortfolioAccount,
+    #[msg("Margin account has no debt")]
+    NoDebt,
+    #[msg("Margin account has outstanding debt")]
+    OutstandingDebt,
+    #[msg("Funding was already settled for this account within the funding interval")]
+    FundingNotDue,
+    #[msg("Account data does not fit its header and entries")]
+    InvalidAccountLength,
+    #[msg("Margin account holds the maximum number of resting orders")]
+    TooManyOpenOrders,
+    #[msg("Market is halted and accepts no new orders")]
+    MarketHalted,
+    #[msg("Market must be halted first")]
+    MarketNotHalted,
 }
@@ -0,0 +1,378 @@
+use crate::errors::ErrorCode;
+use crate::state::{PositionLeg, Side};
+use crate::utils::load_with_tail_mut;
+use anchor_lang::prelude::*;
+use std::cell::RefMut;
+use std::ops::{Deref, DerefMut};
+
+/// Bytes reserved for each serialized event
+pub const EVENT_SIZE: usize = 192;
+
+/// What happened on the market, in native price and quantity units
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub enum QueueEvent {
+    /// An order came to rest on the book
+    Place {
+        owner: Pubkey,
+        side: Side,
+        key: u128,
+        price: u64,
+        qty: u64,
+        client_order_id: u64,
+        position_leg: Option<PositionLeg>,
+    },
+    /// A taker order traded against a resting order at the maker's price.
+    /// The taker side is applied when matched, the maker side on consume.
+    Fill {
+        maker: Pubkey,
+        taker: Pubkey,
+        /// Side of the aggressive order
+        taker_side: Side,
+        /// Book key of the maker order
+        key: u128,
+        price: u64,
+        qty: u64,
+        maker_client_order_id: u64,
+        taker_client_order_id: u64,
+        maker_leg: Option<PositionLeg>,
+        taker_leg: Option<PositionLeg>,
+        maker_fee: u64,
+        taker_fee: u64,
+        /// The fill emptied the maker order and took it off the book
+        maker_out: bool,
+    },
+    /// A resting order left the book without trading
+    Out {
+        owner: Pubkey,
+        side: Side,
+        key: u128,
+        price: u64,
+        qty: u64,
+        client_order_id: u64,
+        position_leg: Option<PositionLeg>,
+    },
+    /// A liquidation unwound part of a position against a resting order.
+    /// The liquidated side is applied immediately, the maker side on consume.
+    Liquidation {
+        maker: Pubkey,
+        liquidated: Pubkey,
+        liquidator: Pubkey,
+        /// Side of the resting order
+        maker_side: Side,
+        key: u128,
+        price: u64,
+        qty: u64,
+        maker_client_order_id: u64,
+        maker_leg: Option<PositionLeg>,
+        /// Liquidation fee charged to the liquidated account on this trade
+        fee: u64,
+        /// The fill emptied the maker order and took it off the book
+        maker_out: bool,
+    },
+    /// Funding settled for one account; positive when it received funding
+    Funding {
+        owner: Pubkey,
+        mark_price: i128,
+        payment: i64,
+    },
+}
+
+/// A queued event stamped with its place in the market's history
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct EventRecord {
+    /// Gapless per-market sequence number, starting at 0
+    pub seq_num: u64,
+    pub slot: u64,
+    pub timestamp: i64,
+    pub event: QueueEvent,
+}
+
+/// One borsh-encoded event, zero padded to `EVENT_SIZE`
+
+#[account(zero_copy)]
+#[repr(C)]
+pub struct EventSlot {
+    pub data: [u8; EVENT_SIZE],
+}
+
+/// Zero-copy ring buffer header of orderbook events, followed in the
+/// account by as many `EventSlot`s as the account holds.
+/// Events are appended at `head + count` and consumed from `head`, both
+/// wrapping at `capacity`. A full queue rejects new events instead of
+/// overwriting unconsumed ones.
+
+#[account(zero_copy)]
+#[repr(C)]
+pub struct EventQueue {
+    pub market: Pubkey,    // 32 bytes
+    pub head: u32,         // slot of the oldest event
+    pub count: u32,        // unconsumed events
+    pub capacity: u32,     // usable slots
+    pub _padding: [u8; 4], // pad to 8-byte multiple
+    pub seq_num: u64,      // sequence number of the next event
+}
+
+impl EventQueue {
+    /// Account size for a queue holding `capacity` events
+    pub fn space(capacity: usize) -> usize {
+        8 + std::mem::size_of::<EventQueue>() + capacity * std::mem::size_of::<EventSlot>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count >= self.capacity
+    }
+}
+
+/// An event queue header and the slots behind it, borrowed mutably from the
+/// queue account
+pub struct EventQueueView<'a> {
+    pub header: RefMut<'a, EventQueue>,
+    pub events: RefMut<'a, [EventSlot]>,
+}
+
+impl Deref for EventQueueView<'_> {
+    type Target = EventQueue;
+
+    fn deref(&self) -> &EventQueue {
+        &self.header
+    }
+}
+
+impl DerefMut for EventQueueView<'_> {
+    fn deref_mut(&mut self) -> &mut EventQueue {
+        &mut self.header
+    }
+}
+
+impl<'a> EventQueueView<'a> {
+    /// Borrow an initialized queue account
+    pub fn load_mut(loader: &'a AccountLoader<'_, EventQueue>) -> Result<Self> {
+        let (header, events) = load_with_tail_mut(loader, false)?;
+        Ok(Self { header, events })
+    }
+
+    /// Borrow a freshly created queue account for `init`
+    pub fn load_init(loader: &'a AccountLoader<'_, EventQueue>) -> Result<Self> {
+        let (header, events) = load_with_tail_mut(loader, true)?;
+        Ok(Self { header, events })
+    }
+
+    /// Reset the queue for `market` with room for `capacity` events, which
+    /// the account must hold
+    pub fn init(&mut self, market: Pubkey, capacity: usize) -> Result<()> {
+        require!(
+            capacity > 0 && capacity <= self.events.len(),
+            ErrorCode::InvalidEventQueueCapacity
+        );
+        let header = &mut self.header;
+        header.market = market;
+        header.head = 0;
+        header.count = 0;
+        header.capacity = capacity as u32;
+        header.seq_num = 0;
+        Ok(())
+    }
+
+    /// Append `event` under the next sequence number, stamped with the
+    /// current slot and time
+    pub fn push(&mut self, event: QueueEvent) -> Result<()> {
+        let clock = Clock::get()?;
+        self.push_at(event, clock.slot, clock.unix_timestamp)
+    }
+
+    /// Append `event` under the next sequence number, failing once the queue
+    /// is full so matching stops until the queue is consumed
+    pub fn push_at(&mut self, event: QueueEvent, slot: u64, timestamp: i64) -> Result<()> {
+        require!(!self.is_full(), ErrorCode::EventQueueFull);
+        let record = EventRecord {
+            seq_num: self.seq_num,
+            slot,
+            timestamp,
+            event,
+        };
+        let data = record
+            .try_to_vec()
+            .map_err(|_| error!(ErrorCode::EventSerializationFailure))?;
+        require!(
+            data.len() <= EVENT_SIZE,
+            ErrorCode::EventSerializationFailure
+        );
+        let header = &mut self.header;
+        let idx = ((header.head + header.count) % header.capacity) as usize;
+        header.count += 1;
+        header.seq_num += 1;
+        let buf = &mut self.events[idx].data;
+        buf[..data.len()].copy_from_slice(&data);
+        buf[data.len()..].fill(0);
+        Ok(())
+    }
+
+    /// Oldest unconsumed event, if any
+    pub fn peek(&self) -> Result<Option<EventRecord>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let mut data: &[u8] = &self.events[self.head as usize].data;
+        let event = EventRecord::deserialize(&mut data)
+            .map_err(|_| error!(ErrorCode::EventDeserializationFailure))?;
+        Ok(Some(event))
+    }
+
+    /// Drop the oldest event once it has been processed
+    pub fn pop(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let header = &mut self.header;
+        header.head = (header.head + 1) % header.capacity;
+        header.count -= 1;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use anchor_lang::solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    pub(crate) fn with_queue(slots: usize, capacity: usize, f: impl FnOnce(&mut EventQueueView)) {
+        let header = RefCell::new(<EventQueue as bytemuck::Zeroable>::zeroed());
+        let events = RefCell::new(vec![<EventSlot as bytemuck::Zeroable>::zeroed(); slots]);
+        let mut queue = EventQueueView {
+            header: header.borrow_mut(),
+            events: RefMut::map(events.borrow_mut(), |e| e.as_mut_slice()),
+        };
+        queue.init(Pubkey::default(), capacity).unwrap();
+        f(&mut queue);
+    }
+
+    struct ClockStub;
+
+    impl SyscallStubs for ClockStub {
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            // SAFETY: the runtime passes a buffer sized for `Clock`
+            unsafe { *(var_addr as *mut Clock) = Clock::default() };
+            0
+        }
+    }
+
+    /// Let `Clock::get`, and so `push`, run outside the runtime
+    pub(crate) fn stub_clock() {
+        static STUB: Once = Once::new();
+        STUB.call_once(|| {
+            set_syscall_stubs(Box::new(ClockStub));
+        });
+    }
+
+    fn funding(payment: i64) -> QueueEvent {
+        QueueEvent::Funding {
+            owner: Pubkey::default(),
+            mark_price: 0,
+            payment,
+        }
+    }
+
+    fn payment(record: &EventRecord) -> i64 {
+        match record.event {
+            QueueEvent::Funding { payment, .. } => payment,
+            _ => panic!("unexpected event"),
+        }
+    }
+
+    #[test]
+    fn space_matches_layout() {
+        assert_eq!(std::mem::size_of::<EventQueue>(), 56);
+        assert_eq!(EventQueue::space(256), 8 + 56 + 256 * EVENT_SIZE);
+    }
+
+    #[test]
+    fn largest_events_fit_a_slot() {
+        stub_clock();
+        with_queue(2, 2, |queue| {
+            let leg = Some(PositionLeg::CloseShort);
+            queue
+                .push(QueueEvent::Fill {
+                    maker: Pubkey::default(),
+                    taker: Pubkey::default(),
+                    taker_side: Side::Bid,
+                    key: u128::MAX,
+                    price: u64::MAX,
+                    qty: u64::MAX,
+                    maker_client_order_id: u64::MAX,
+                    taker_client_order_id: u64::MAX,
+                    maker_leg: leg,
+                    taker_leg: leg,
+                    maker_fee: u64::MAX,
+                    taker_fee: u64::MAX,
+                    maker_out: true,
+                })
+                .unwrap();
+            queue
+                .push(QueueEvent::Liquidation {
+                    maker: Pubkey::default(),
+                    liquidated: Pubkey::default(),
+                    liquidator: Pubkey::default(),
+                    maker_side: Side::Ask,
+                    key: u128::MAX,
+                    price: u64::MAX,
+                    qty: u64::MAX,
+                    maker_client_order_id: u64::MAX,
+                    maker_leg: leg,
+                    fee: u64::MAX,
+                    maker_out: true,
+                })
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn init_rejects_capacity_beyond_account() {
+        let header = RefCell::new(<EventQueue as bytemuck::Zeroable>::zeroed());
+        let events = RefCell::new(vec![<EventSlot as bytemuck::Zeroable>::zeroed(); 4]);
+        let mut queue = EventQueueView {
+            header: header.borrow_mut(),
+            events: RefMut::map(events.borrow_mut(), |e| e.as_mut_slice()),
+        };
+        assert!(queue.init(Pubkey::default(), 5).is_err());
+        assert!(queue.init(Pubkey::default(), 0).is_err());
+        assert!(queue.init(Pubkey::default(), 4).is_ok());
+    }
+
+    #[test]
+    fn events_wrap_in_order() {
+        with_queue(4, 3, |queue| {
+            for round in 0..3i64 {
+                queue.push_at(funding(2 * round), 1, 1).unwrap();
+                queue.push_at(funding(2 * round + 1), 1, 1).unwrap();
+                for i in 0..2 {
+                    let record = queue.peek().unwrap().unwrap();
+                    assert_eq!(record.seq_num, (2 * round + i) as u64);
+                    assert_eq!(payment(&record), 2 * round + i);
+                    queue.pop();
+                }
+            }
+            assert!(queue.is_empty());
+            assert_eq!(queue.seq_num, 6);
+            assert!(queue.peek().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn full_queue_rejects_push() {
+        with_queue(2, 2, |queue| {
+            queue.push_at(funding(0), 1, 1).unwrap();
+            queue.push_at(funding(1), 1, 1).unwrap();
+            assert!(queue.is_full());
+            assert!(queue.push_at(funding(2), 1, 1).is_err());
+            assert_eq!(queue.seq_num, 2);
+            queue.pop();
+            queue.push_at(funding(2), 1, 1).unwrap();
+            assert_eq!(payment(&queue.peek().unwrap().unwrap()), 1);
+        });
+    }
+}
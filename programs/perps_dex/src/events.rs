@@ -0,0 +1,108 @@
+use crate::state::{OrderType, Side};
+use anchor_lang::prelude::*;
+
+// Program events for indexers. Prices and quantities are in native units.
+
+/// A limit order was matched and, if anything was left, rested on the book
+#[event]
+pub struct OrderPlaced {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub side: Side,
+    pub order_type: OrderType,
+    /// Limit price, after any post-only slide
+    pub price: u64,
+    pub qty: u64,
+    pub filled_qty: u64,
+    /// Book key of the resting remainder, if one was placed
+    pub resting_key: Option<u128>,
+    pub resting_qty: u64,
+    pub client_order_id: u64,
+}
+
+/// A market order swept the book up to its slippage bound
+#[event]
+pub struct MarketOrderPlaced {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub side: Side,
+    /// Worst price the order was allowed to trade at
+    pub limit_price: u64,
+    pub qty: u64,
+    pub filled_qty: u64,
+    pub client_order_id: u64,
+}
+
+/// A taker order traded against a resting order at the maker's price
+#[event]
+pub struct TradeExecuted {
+    pub market: Pubkey,
+    /// Sequence number of the matching fill in the event queue
+    pub seq_num: u64,
+    pub maker: Pubkey,
+    pub taker: Pubkey,
+    pub taker_side: Side,
+    pub maker_order_key: u128,
+    pub price: u64,
+    pub qty: u64,
+    pub maker_fee: u64,
+    pub taker_fee: u64,
+}
+
+/// Positions of an unhealthy account were unwound against the book
+#[event]
+pub struct Liquidated {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub liquidator: Pubkey,
+    pub mark_price: i128,
+    /// Side of the book the positions were closed into
+    pub book_side: Side,
+    pub qty: u64,
+    pub notional: u128,
+    /// Fee paid by the liquidated account to the liquidator
+    pub fee: u64,
+    pub collateral_after: u64,
+}
+
+/// Funding was settled for one account; positive when it received funding
+#[event]
+pub struct FundingSettled {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub mark_price: i128,
+    pub payment: i64,
+}
+
+/// Collateral moved into a margin account
+#[event]
+pub struct CollateralDeposited {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    /// Amount credited, after any transfer fee
+    pub amount: u64,
+    /// Account balance of `mint` afterwards
+    pub balance: u64,
+}
+
+/// Collateral moved out of a margin account
+#[event]
+pub struct CollateralWithdrawn {
+    pub owner: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    /// Account balance of `mint` afterwards
+    pub balance: u64,
+}
+
+/// Listed collateral was sold to a liquidator repaying the account's debt
+#[event]
+pub struct CollateralLiquidated {
+    pub owner: Pubkey,
+    pub liquidator: Pubkey,
+    pub mint: Pubkey,
+    /// Quote that arrived in the vault
+    pub repaid: u64,
+    pub seized: u64,
+    pub debt_after: u64,
+}
@@ -1,30 +1,51 @@
-use crate::state::{EventQueue, MarginAccount, Market};
+use crate::event_queue::EventQueue;
+use crate::slab::Slab;
+use crate::state::{MarginAccount, Market, Side};
 use anchor_lang::prelude::*;
 
 #[derive(Accounts)]
-#[instruction()]
 pub struct InitializeEventQueue<'info> {
-    #[account(
-        init,
-        payer = authority,
-        seeds = [b"eventqueue", market.key().as_ref()],
-        bump,
-        space = 8 + 5000,
-    )]
-    pub event_queue: Account<'info, EventQueue>,
+    /// Zero-copy ring buffer, created by the client with
+    /// `EventQueue::space(capacity)`
+    #[account(zero)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+    #[account(mut, has_one = authority)]
     pub market: Account<'info, Market>,
-    #[account(mut)]
     pub authority: Signer<'info>,
-    pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct CloseEventQueue<'info> {
+    #[account(mut, address = market.event_queue, close = authority)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+    #[account(mut, has_one = authority)]
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
 
+#[derive(Accounts)]
+pub struct HaltMarket<'info> {
+    #[account(mut, has_one = authority)]
+    pub market: Account<'info, Market>,
+    pub authority: Signer<'info>,
+}
 
 #[derive(Accounts)]
 pub struct UpdateRiskParams<'info> {
     #[account(mut, has_one = authority)]
     pub market: Account<'info, Market>,
     pub authority: Signer<'info>,
+    /// CHECK: bid book of the market, empty if it was never created
+    #[account(seeds = [b"orderbook", market.key().as_ref(), &[Side::Bid as u8]], bump)]
+    pub bids: UncheckedAccount<'info>,
+    /// CHECK: ask book of the market, empty if it was never created
+    #[account(seeds = [b"orderbook", market.key().as_ref(), &[Side::Ask as u8]], bump)]
+    pub asks: UncheckedAccount<'info>,
+    /// Required with a tick or lot size change once the bid book exists
+    pub bid_slab: Option<AccountLoader<'info, Slab>>,
+    /// Required with a tick or lot size change once the ask book exists
+    pub ask_slab: Option<AccountLoader<'info, Slab>>,
 }
 
 // Governance Token Initialization
@@ -1,6 +1,8 @@
 pub mod collateral;
 pub mod dao;
 pub mod errors;
+pub mod event_queue;
+pub mod events;
 pub mod instructions;
 pub mod liquidate_engine;
 pub mod margin;
@@ -44,13 +46,59 @@ pub mod perps_dex {
         orderbook::initialize_orderbook(ctx, side, capacity)
     }
 
-    pub fn initialize_event_queue(ctx: Context<InitializeEventQueue>) -> Result<()> {
-        utils::initialize_event_queue(ctx)
+    pub fn initialize_event_queue(ctx: Context<InitializeEventQueue>, capacity: u32) -> Result<()> {
+        utils::initialize_event_queue(ctx, capacity)
     }
+    pub fn close_orderbook(ctx: Context<CloseOrderbook>) -> Result<()> {
+        orderbook::close_orderbook(ctx)
+    }
+
+    pub fn close_event_queue(ctx: Context<CloseEventQueue>) -> Result<()> {
+        utils::close_event_queue(ctx)
+    }
+
     pub fn initialize_margin(ctx: Context<InitializeMargin>) -> Result<()> {
         margin::initialize_margin(ctx)
     }
 
+    pub fn close_margin_account(ctx: Context<CloseMarginAccount>) -> Result<()> {
+        margin::close_margin_account(ctx)
+    }
+
+    pub fn set_delegate(ctx: Context<SetDelegate>, delegate: Option<Pubkey>) -> Result<()> {
+        margin::set_delegate(ctx, delegate)
+    }
+
+    pub fn set_position_mode(
+        ctx: Context<SetPositionMode>,
+        mode: state::PositionMode,
+    ) -> Result<()> {
+        margin::set_position_mode(ctx, mode)
+    }
+
+    pub fn set_margin_type(
+        ctx: Context<SetMarginType>,
+        margin_type: state::MarginType,
+    ) -> Result<()> {
+        margin::set_margin_type(ctx, margin_type)
+    }
+
+    pub fn add_isolated_collateral(
+        ctx: Context<AdjustIsolatedCollateral>,
+        side: state::Side,
+        amount: u64,
+    ) -> Result<()> {
+        margin::add_isolated_collateral(ctx, side, amount)
+    }
+
+    pub fn remove_isolated_collateral(
+        ctx: Context<AdjustIsolatedCollateral>,
+        side: state::Side,
+        amount: u64,
+    ) -> Result<()> {
+        margin::remove_isolated_collateral(ctx, side, amount)
+    }
+
     pub fn deposit_collateral(ctx: Context<DepositCollateral>, amount: u64) -> Result<()> {
         collateral::deposit_collateral(ctx, amount)
     }
@@ -59,13 +107,89 @@ pub mod perps_dex {
         collateral::withdraw_collateral(ctx, amount)
     }
 
+    pub fn initialize_collateral_registry(
+        ctx: Context<InitializeCollateralRegistry>,
+    ) -> Result<()> {
+        collateral::initialize_collateral_registry(ctx)
+    }
+
+    pub fn liquidate_collateral(ctx: Context<LiquidateCollateral>, amount: u64) -> Result<()> {
+        collateral::liquidate_collateral(ctx, amount)
+    }
+
+    pub fn list_collateral(
+        ctx: Context<ListCollateral>,
+        weight_bps: u16,
+        deposit_cap: u64,
+        oracle_max_age: u64,
+    ) -> Result<()> {
+        collateral::list_collateral(ctx, weight_bps, deposit_cap, oracle_max_age)
+    }
+
+    pub fn update_collateral(
+        ctx: Context<UpdateCollateral>,
+        weight_bps: u16,
+        deposit_cap: u64,
+    ) -> Result<()> {
+        collateral::update_collateral(ctx, weight_bps, deposit_cap)
+    }
+
+    pub fn deposit_listed_collateral(
+        ctx: Context<TransferListedCollateral>,
+        amount: u64,
+    ) -> Result<()> {
+        collateral::deposit_listed_collateral(ctx, amount)
+    }
+
+    pub fn withdraw_listed_collateral(
+        ctx: Context<TransferListedCollateral>,
+        amount: u64,
+    ) -> Result<()> {
+        collateral::withdraw_listed_collateral(ctx, amount)
+    }
+
     pub fn place_limit_order(
         ctx: Context<PlaceLimitOrder>,
         side: state::Side,
         price: u64,
         qty: u64,
+        order_type: state::OrderType,
+        client_order_id: Option<u64>,
+        position_leg: Option<state::PositionLeg>,
     ) -> Result<()> {
-        order::place_limit_order(ctx, side, price, qty)
+        order::place_limit_order(
+            ctx,
+            side,
+            price,
+            qty,
+            order_type,
+            client_order_id,
+            position_leg,
+        )
+    }
+
+    pub fn cancel_order(
+        ctx: Context<CancelOrder>,
+        side: state::Side,
+        order_key: u128,
+    ) -> Result<()> {
+        order::cancel_order(ctx, side, order_key)
+    }
+
+    pub fn cancel_order_by_client_id(
+        ctx: Context<CancelOrder>,
+        side: state::Side,
+        client_order_id: u64,
+    ) -> Result<()> {
+        order::cancel_order_by_client_id(ctx, side, client_order_id)
+    }
+
+    pub fn cancel_all_orders(
+        ctx: Context<CancelAllOrders>,
+        side: Option<state::Side>,
+        limit: u8,
+    ) -> Result<()> {
+        order::cancel_all_orders(ctx, side, limit)
     }
 
     pub fn place_market_order(
@@ -73,8 +197,24 @@ pub mod perps_dex {
         qty: u64,
         side: state::Side,
         max_slippage_bps: u16,
+        position_leg: Option<state::PositionLeg>,
+        client_order_id: Option<u64>,
+    ) -> Result<()> {
+        order::place_market_order(
+            ctx,
+            qty,
+            side,
+            max_slippage_bps,
+            position_leg,
+            client_order_id,
+        )
+    }
+
+    pub fn consume_events<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ConsumeEvents<'info>>,
+        limit: u16,
     ) -> Result<()> {
-        order::place_market_order(ctx, qty, side, max_slippage_bps)
+        settles::consume_events(ctx, limit)
     }
 
     pub fn settle_funding(ctx: Context<SettleFunding>) -> Result<()> {
@@ -85,6 +225,10 @@ pub mod perps_dex {
         liquidate_engine::liquidate(ctx)
     }
 
+    pub fn halt_market(ctx: Context<HaltMarket>) -> Result<()> {
+        utils::halt_market(ctx)
+    }
+
     pub fn update_risk_params(
         ctx: Context<UpdateRiskParams>,
         new_params: state::MarketParams,
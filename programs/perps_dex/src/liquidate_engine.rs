@@ -1,10 +1,16 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Transfer};
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
 
 use crate::errors::ErrorCode;
-use crate::slab::Slab;
-use crate::state::{MarginAccount, Market, OrderbookSide, Side};
-use crate::utils::get_mark_price;
+use crate::event_queue::{EventQueue, EventQueueView, QueueEvent};
+use crate::events::Liquidated;
+use crate::margin::{is_below_maintenance, load_portfolio};
+use crate::slab::{Slab, SlabView};
+use crate::state::{
+    CollateralRegistry, MarginAccount, MarginType, Market, MarketParams, OrderbookSide,
+    PositionLeg, PositionMode, Side, BPS,
+};
+use crate::utils::{get_mark_price, transfer_collateral};
 
 #[derive(Accounts)]
 pub struct LiquidateEngine<'info> {
@@ -12,26 +18,32 @@ pub struct LiquidateEngine<'info> {
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub margin: Account<'info, MarginAccount>,
-    #[account(mut)]
-    pub orderbook_side: Account<'info, OrderbookSide>,
     #[account(
         mut,
-        seeds = [b"slab", orderbook_side.key().as_ref()],
+        seeds = [b"orderbook", market.key().as_ref(), &[orderbook_side.side as u8]],
         bump = orderbook_side.bump
     )]
+    pub orderbook_side: Account<'info, OrderbookSide>,
+    #[account(mut, address = orderbook_side.slab)]
     pub slab: AccountLoader<'info, Slab>,
-    /// CHECK:
-    #[account(mut)]
+    #[account(mut, address = market.event_queue)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+    /// CHECK: the market's Pyth feed
+    #[account(address = market.oracle_pyth)]
     pub oracle_pyth: AccountInfo<'info>,
-    /// CHECK:
-    #[account(mut)]
+    /// CHECK: the market's Switchboard feed
+    #[account(address = market.oracle_switchboard)]
     pub oracle_switch: AccountInfo<'info>,
     pub liquidator: Signer<'info>,
+    #[account(seeds = [b"collateral_registry"], bump = registry.bump)]
+    pub registry: Account<'info, CollateralRegistry>,
+    #[account(address = registry.quote_mint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
     #[account(mut)]
-    pub liquidator_collateral_account: Account<'info, anchor_spl::token::TokenAccount>,
-    #[account(mut)]
-    pub collateral_vault: Account<'info, anchor_spl::token::TokenAccount>,
-    pub token_program: Program<'info, anchor_spl::token::Token>,
+    pub liquidator_collateral_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, address = registry.quote_vault)]
+    pub quote_vault: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 pub fn liquidate(ctx: Context<LiquidateEngine>) -> Result<()> {
@@ -39,51 +51,124 @@ pub fn liquidate(ctx: Context<LiquidateEngine>) -> Result<()> {
     let mut pyth_ai = ctx.accounts.oracle_pyth.clone();
     let mut sb_ai = ctx.accounts.oracle_switch.clone();
     let params = &ctx.accounts.market.params;
-    let max_age = ctx.accounts.market.params.funding_interval;
-    let mark_price = get_mark_price(&mut pyth_ai, &mut sb_ai, max_age, 5, 3)?;
+    let mark_price = get_mark_price(&ctx.accounts.market, &mut pyth_ai, &mut sb_ai, 5, 3)?;
 
-    // compute equity & notional
+    // maintenance margin check: over every market the account trades in
+    // cross mode, and per position on this market in isolated mode so one
+    // position cannot drag the others down
+    let market_key = ctx.accounts.market.key();
     let margin = &mut ctx.accounts.margin;
-    let mut equity: i128 = margin.collateral as i128;
-    let mut notional: i128 = 0;
-    for pos in margin.positions.iter() {
-        let entry = pos.entry_price as i128;
-        let sign = if pos.side == Side::Bid { 1 } else { -1 };
-        let pnl = (mark_price - entry).saturating_mul(pos.qty as i128) * sign;
-        equity = equity.saturating_add(pnl);
-        notional = notional.saturating_add((entry.saturating_mul(pos.qty as i128)).abs());
-    }
-
-    // maintenance margin check
-    let health = if notional > 0 {
-        equity.saturating_mul(10_000) / notional
-    } else {
-        0
+    let liquidatable: Vec<bool> = match margin.margin_type {
+        MarginType::Cross => {
+            let portfolio = load_portfolio(margin, &market_key, ctx.remaining_accounts)?;
+            let unhealthy =
+                is_below_maintenance(margin, params, &market_key, mark_price, &portfolio)?;
+            margin
+                .positions
+                .iter()
+                .map(|p| unhealthy && p.market == market_key && p.qty > 0)
+                .collect()
+        }
+        MarginType::Isolated => margin
+            .positions
+            .iter()
+            .map(|pos| {
+                let entry = pos.entry_price as i128;
+                let sign = if pos.side == Side::Bid { 1 } else { -1 };
+                let pnl = (mark_price - entry).saturating_mul(pos.qty as i128) * sign;
+                let equity = (pos.collateral as i128).saturating_add(pnl);
+                let notional = (pos.entry_price as u128).saturating_mul(pos.qty as u128);
+                pos.market == market_key && pos.qty > 0 && is_unhealthy(params, equity, notional)
+            })
+            .collect(),
     };
-    require!(
-        health < params.maintenance_margin_ratio as i128,
-        ErrorCode::HealthyAccount
-    );
+    require!(liquidatable.contains(&true), ErrorCode::HealthyAccount);
 
-    // unwind positions via in-place slab
-    let mut slab = ctx.accounts.slab.load_mut()?;
-    let mut total_proceeds: u128 = 0;
-    for pos in margin.positions.iter() {
-        let mut rem = pos.qty;
+    // unwind positions via in-place slab, which is kept in tick/lot units.
+    // Longs are sold into the bids and shorts bought back from the asks, so
+    // only the positions (or hedge legs) this book can close are unwound.
+    // Each trade is netted into the position like any other fill
+    let book_side = ctx.accounts.orderbook_side.side;
+    let targets: Vec<usize> = (0..margin.positions.len())
+        .filter(|&i| liquidatable[i] && margin.positions[i].side == book_side)
+        .collect();
+    require!(!targets.is_empty(), ErrorCode::InvalidOrderbookSide);
+    let band = liquidation_band(params, mark_price, book_side)?;
+    let hedge = margin.position_mode == PositionMode::Hedge;
+    let mut slab = SlabView::load_mut(&ctx.accounts.slab)?;
+    let mut queue = EventQueueView::load_mut(&ctx.accounts.event_queue)?;
+    let liquidator = ctx.accounts.liquidator.key();
+    let mut fee: u64 = 0;
+    let (mut unwound_qty, mut unwound_notional) = (0u64, 0u128);
+    for i in targets {
+        let (pos_side, qty) = (margin.positions[i].side, margin.positions[i].qty);
+        let leg = hedge.then_some(match pos_side {
+            Side::Bid => PositionLeg::CloseLong,
+            Side::Ask => PositionLeg::CloseShort,
+        });
+        let mut rem = qty / params.lot_size;
         while rem > 0 {
-            if let Some(idx) = slab.best() {
+            // orders beyond the band are left alone, so a thin book cannot
+            // hand the position over far from its mark
+            let within_band = |price: u64| match book_side {
+                Side::Bid => price >= band,
+                Side::Ask => price <= band,
+            };
+            if let Some(idx) = slab
+                .best()
+                .filter(|&idx| within_band(slab.nodes[idx as usize].price))
+            {
                 // temporarily mutate node and capture data, then drop borrow
-                let (price, trade_qty, emptied) = {
+                let (key, price, trade_qty, emptied, maker, maker_leg, maker_client_order_id) = {
                     let node = &mut slab.nodes[idx as usize];
                     let tq = rem.min(node.qty);
                     node.qty -= tq;
-                    (node.price, tq, node.qty == 0)
+                    let leg = PositionLeg::decode(node.position_leg);
+                    (
+                        node.key,
+                        node.price,
+                        tq,
+                        node.qty == 0,
+                        node.owner,
+                        leg,
+                        node.client_order_id,
+                    )
                 };
                 if emptied {
                     slab.remove(idx)?;
                 }
-                total_proceeds = total_proceeds
-                    .saturating_add((trade_qty as u128).saturating_mul(price as u128));
+                let price = params.ticks_to_price(price)?;
+                let fill_qty = params.lots_to_qty(trade_qty)?;
+                // 0.5% liquidation fee on each trade, paid like the loss
+                // from isolated collateral first
+                let trade_fee = u64::try_from(price as u128 * fill_qty as u128 / 200)
+                    .map_err(|_| error!(ErrorCode::Overflow))?;
+                margin.apply_fill(
+                    market_key,
+                    pos_side.opposite(),
+                    price,
+                    fill_qty,
+                    leg,
+                    trade_fee,
+                )?;
+                fee = fee.saturating_add(trade_fee);
+                unwound_qty = unwound_qty.saturating_add(fill_qty);
+                unwound_notional =
+                    unwound_notional.saturating_add(price as u128 * fill_qty as u128);
+                // the maker's side is applied when the event is consumed
+                queue.push(QueueEvent::Liquidation {
+                    maker,
+                    liquidated: margin.owner,
+                    liquidator,
+                    maker_side: book_side,
+                    key,
+                    price,
+                    qty: fill_qty,
+                    maker_client_order_id,
+                    maker_leg,
+                    fee: trade_fee,
+                    maker_out: emptied,
+                })?;
                 rem = rem.saturating_sub(trade_qty);
             } else {
                 break;
@@ -91,31 +176,77 @@ pub fn liquidate(ctx: Context<LiquidateEngine>) -> Result<()> {
         }
     }
 
+    // nothing resting inside the band: wait for liquidity near the mark
+    require!(unwound_qty > 0, ErrorCode::SlippageExceeded);
+
     // persist updated slab pointers
     let ob = &mut ctx.accounts.orderbook_side;
     ob.head = slab.head;
     ob.free_head = slab.free_head;
 
-    // apply 0.5% liquidation fee
-    let proceeds_u64: u64 = total_proceeds
-        .try_into()
-        .map_err(|_| error!(ErrorCode::Overflow))?;
-    let fee = proceeds_u64 / 200;
     let liquidator_cut = fee;
-    let vault_cut = proceeds_u64.saturating_sub(fee);
 
     // transfer liquidator share
-    let cpi_accounts = Transfer {
-        from: ctx.accounts.collateral_vault.to_account_info(),
-        to: ctx.accounts.liquidator_collateral_account.to_account_info(),
-        authority: ctx.accounts.market.to_account_info(),
-    };
-    let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-    token::transfer(cpi_ctx, liquidator_cut)?;
-
-    // clear positions and set collateral
-    margin.positions.clear();
-    margin.collateral = vault_cut;
+    let seeds: &[&[u8]] = &[b"collateral_registry", &[ctx.accounts.registry.bump]];
+    transfer_collateral(
+        &ctx.accounts.token_program,
+        &ctx.accounts.quote_vault,
+        &ctx.accounts.liquidator_collateral_account,
+        ctx.accounts.registry.to_account_info(),
+        &ctx.accounts.quote_mint,
+        liquidator_cut,
+        &[seeds],
+    )?;
 
+    emit!(Liquidated {
+        market: market_key,
+        owner: margin.owner,
+        liquidator,
+        mark_price,
+        book_side,
+        qty: unwound_qty,
+        notional: unwound_notional,
+        fee,
+        collateral_after: margin.collateral,
+    });
     Ok(())
 }
+
+/// Worst price in ticks a liquidation may trade at on `book_side`: the
+/// maintenance margin below the mark when selling into the bids, above it
+/// when buying back from the asks
+fn liquidation_band(params: &MarketParams, mark_price: i128, book_side: Side) -> Result<u64> {
+    let mark = u128::try_from(mark_price).map_err(|_| error!(ErrorCode::InvalidPriceFeed))?;
+    let bps = params.maintenance_margin_bps as u128;
+    let per_tick = BPS * params.tick_size as u128;
+    let ticks = match book_side {
+        Side::Bid => mark.checked_mul(BPS - bps).map(|p| p.div_ceil(per_tick)),
+        Side::Ask => mark.checked_mul(BPS + bps).map(|p| p / per_tick),
+    }
+    .ok_or(error!(ErrorCode::Overflow))?;
+    u64::try_from(ticks).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Whether `equity` has fallen below maintenance margin on `notional`
+fn is_unhealthy(params: &MarketParams, equity: i128, notional: u128) -> bool {
+    equity < 0 || (equity as u128) < params.maintenance_margin(notional)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::params;
+
+    #[test]
+    fn liquidation_band_is_maintenance_margin_around_mark() {
+        // 2.5% maintenance
+        let mut p = params(&[]);
+        assert_eq!(liquidation_band(&p, 1_000, Side::Bid).unwrap(), 975);
+        assert_eq!(liquidation_band(&p, 1_000, Side::Ask).unwrap(), 1_025);
+        // bounds round inwards onto the tick grid
+        p.tick_size = 10;
+        assert_eq!(liquidation_band(&p, 1_001, Side::Bid).unwrap(), 98);
+        assert_eq!(liquidation_band(&p, 1_001, Side::Ask).unwrap(), 102);
+        assert!(liquidation_band(&p, -1, Side::Bid).is_err());
+    }
+}
@@ -1,19 +1,21 @@
 use anchor_lang::prelude::*;
 
-use crate::state::{MarginAccount, MarginType, Market};
+use crate::errors::ErrorCode;
+use crate::state::{
+    CollateralConfig, MarginAccount, MarginType, Market, MarketParams, Position, PositionLeg,
+    PositionMode, Side,
+};
+use crate::utils::{get_mark_price, get_optional_mark_price, get_oracle_price};
 
 #[derive(Accounts)]
 #[instruction()]
 pub struct InitializeMargin<'info> {
-    #[account(mut)]
-    pub market: Account<'info, Market>,
-
     #[account(
       init,
       payer = user,
-      seeds = [b"margin", market.key().as_ref(), user.key().as_ref()],
+      seeds = [b"margin", user.key().as_ref()],
       bump,
-      space = 8 + std::mem::size_of::<MarginAccount>(),
+      space = 8 + MarginAccount::INIT_SPACE,
     )]
     pub margin: Account<'info, MarginAccount>,
 
@@ -23,12 +25,855 @@ pub struct InitializeMargin<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct CloseMarginAccount<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin", user.key().as_ref()],
+        bump = margin.bump,
+        close = user
+    )]
+    pub margin: Account<'info, MarginAccount>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPositionMode<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin", user.key().as_ref()],
+        bump = margin.bump
+    )]
+    pub margin: Account<'info, MarginAccount>,
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetMarginType<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin", user.key().as_ref()],
+        bump = margin.bump
+    )]
+    pub margin: Account<'info, MarginAccount>,
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetDelegate<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin", user.key().as_ref()],
+        bump = margin.bump
+    )]
+    pub margin: Account<'info, MarginAccount>,
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AdjustIsolatedCollateral<'info> {
+    #[account(
+        mut,
+        seeds = [b"margin", user.key().as_ref()],
+        bump = margin.bump
+    )]
+    pub margin: Account<'info, MarginAccount>,
+    pub user: Signer<'info>,
+    pub market: Account<'info, Market>,
+    /// CHECK: only needed to price an open position when removing collateral
+    #[account(address = market.oracle_pyth)]
+    pub oracle_pyth: Option<AccountInfo<'info>>,
+    /// CHECK: only needed to price an open position when removing collateral
+    #[account(address = market.oracle_switchboard)]
+    pub oracle_switchboard: Option<AccountInfo<'info>>,
+}
+
 pub fn initialize_margin(ctx: Context<InitializeMargin>) -> Result<()> {
     let m = &mut ctx.accounts.margin;
     m.owner = ctx.accounts.user.key();
+    m.delegate = None;
     m.collateral = 0;
+    m.debt = 0;
+    m.collateral_balances = Vec::new();
     m.margin_type = MarginType::Cross;
+    m.position_mode = PositionMode::OneWay;
     m.positions = Vec::new();
+    m.open_orders = Vec::new();
     m.bump = ctx.bumps.margin;
     Ok(())
 }
+
+/// Close an empty margin account and return its rent to the owner. The
+/// account must hold no collateral of any mint, no isolated collateral, no
+/// debt, and no size or resting orders on any market. An order filled off
+/// the book stays open until the crank consumes its fill, so pending maker
+/// fills also keep the account open.
+pub fn close_margin_account(ctx: Context<CloseMarginAccount>) -> Result<()> {
+    let m = &ctx.accounts.margin;
+    require!(
+        m.collateral == 0
+            && m.debt == 0
+            && m.collateral_balances.is_empty()
+            && m.open_orders.is_empty()
+            && m.is_flat()
+            && m.positions.iter().all(|p| p.collateral == 0),
+        ErrorCode::MarginAccountNotEmpty
+    );
+    Ok(())
+}
+
+/// Let `delegate` place and cancel orders for the owner, or revoke the
+/// current delegate with `None`. Only the owner can call this, and a
+/// delegate can never withdraw collateral.
+pub fn set_delegate(ctx: Context<SetDelegate>, delegate: Option<Pubkey>) -> Result<()> {
+    ctx.accounts.margin.delegate = delegate;
+    Ok(())
+}
+
+/// Switch between one-way and hedge mode; open positions cannot be split
+/// into legs, so the account must be flat.
+pub fn set_position_mode(ctx: Context<SetPositionMode>, mode: PositionMode) -> Result<()> {
+    let m = &mut ctx.accounts.margin;
+    require!(m.is_flat(), ErrorCode::PositionModeLocked);
+    m.reset_positions()?;
+    m.position_mode = mode;
+    msg!("Position mode set to {:?}", mode);
+    Ok(())
+}
+
+/// Switch between cross and isolated margin on a flat account, returning
+/// isolated collateral parked on flat positions to the account.
+pub fn set_margin_type(ctx: Context<SetMarginType>, margin_type: MarginType) -> Result<()> {
+    let m = &mut ctx.accounts.margin;
+    require!(m.is_flat(), ErrorCode::MarginTypeLocked);
+    m.reset_positions()?;
+    m.margin_type = margin_type;
+    msg!("Margin type set to {:?}", margin_type);
+    Ok(())
+}
+
+/// Move `amount` of account collateral onto the isolated position on this
+/// market, or onto its leg on `side` in hedge mode. The position is opened
+/// flat if it does not exist yet, so margin can be posted before trading.
+pub fn add_isolated_collateral(
+    ctx: Context<AdjustIsolatedCollateral>,
+    side: Side,
+    amount: u64,
+) -> Result<()> {
+    require!(amount > 0, ErrorCode::InvalidAmount);
+    let market = ctx.accounts.market.key();
+    let m = &mut ctx.accounts.margin;
+    require!(
+        m.margin_type == MarginType::Isolated,
+        ErrorCode::NotIsolatedMargin
+    );
+    m.collateral = m
+        .collateral
+        .checked_sub(amount)
+        .ok_or(error!(ErrorCode::InsufficientCollateral))?;
+    let idx = m.find_or_open_position(market, side)?;
+    let pos = &mut m.positions[idx];
+    pos.collateral = pos
+        .collateral
+        .checked_add(amount)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    msg!(
+        "Added {} isolated collateral, now {}",
+        amount,
+        pos.collateral
+    );
+    Ok(())
+}
+
+/// Move `amount` of isolated collateral back to the account, as long as the
+/// position stays above initial margin at the mark price
+pub fn remove_isolated_collateral(
+    ctx: Context<AdjustIsolatedCollateral>,
+    side: Side,
+    amount: u64,
+) -> Result<()> {
+    require!(amount > 0, ErrorCode::InvalidAmount);
+    let mark_price = get_optional_mark_price(
+        &ctx.accounts.market,
+        ctx.accounts.oracle_pyth.as_ref(),
+        ctx.accounts.oracle_switchboard.as_ref(),
+    )?;
+    let market = ctx.accounts.market.key();
+    let m = &mut ctx.accounts.margin;
+    require!(
+        m.margin_type == MarginType::Isolated,
+        ErrorCode::NotIsolatedMargin
+    );
+    let idx = m
+        .find_position(&market, side)
+        .ok_or(error!(ErrorCode::PositionNotFound))?;
+    check_isolated_removal(m, &ctx.accounts.market.params, idx, amount, mark_price)?;
+
+    let pos = &mut m.positions[idx];
+    pos.collateral -= amount;
+    m.collateral = m
+        .collateral
+        .checked_add(amount)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    Ok(())
+}
+
+/// Check that an order's position `leg` fits the account's position mode:
+/// one-way orders carry no leg, hedge orders must carry one matching `side`,
+/// and closing legs may not exceed the size held on that leg less what
+/// resting closing orders already claim.
+pub fn validate_position_leg(
+    margin: &MarginAccount,
+    market: &Pubkey,
+    side: Side,
+    qty: u64,
+    leg: Option<PositionLeg>,
+) -> Result<()> {
+    match margin.position_mode {
+        PositionMode::OneWay => require!(leg.is_none(), ErrorCode::InvalidPositionLeg),
+        PositionMode::Hedge => {
+            let leg = leg.ok_or(error!(ErrorCode::InvalidPositionLeg))?;
+            require!(leg.order_side() == side, ErrorCode::InvalidPositionLeg);
+            if leg.is_close() {
+                let available =
+                    margin
+                        .find_position(market, leg.position_side())
+                        .map_or(0, |idx| {
+                            let pos = &margin.positions[idx];
+                            pos.qty.saturating_sub(pos.reserved_close_qty)
+                        });
+                require!(qty <= available, ErrorCode::ReduceOnlyExceeded);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Another market a portfolio account trades, passed in remaining accounts
+pub struct PortfolioMarket {
+    pub key: Pubkey,
+    pub params: MarketParams,
+    /// Oracle mark price, read only where the account holds size
+    pub mark_price: Option<i128>,
+}
+
+/// What backs a cross account: the markets it trades and the value of its
+/// collateral, the quote balance plus every listed mint at its weight
+pub struct Portfolio {
+    pub markets: Vec<PortfolioMarket>,
+    pub collateral_value: u128,
+    /// Quote the account owes, netted against the collateral value
+    pub debt: u64,
+}
+
+impl Portfolio {
+    /// Collateral value less debt plus unrealized `pnl`
+    pub fn equity(&self, pnl: i128) -> Result<i128> {
+        i128::try_from(self.collateral_value)
+            .ok()
+            .and_then(|c| c.checked_sub(self.debt as i128))
+            .and_then(|c| c.checked_add(pnl))
+            .ok_or(error!(ErrorCode::Overflow))
+    }
+}
+
+/// Load the other markets a portfolio account trades and the collateral
+/// mints it holds from `remaining`, as `[market, oracle_pyth,
+/// oracle_switchboard]` or `[collateral_config, oracle_pyth,
+/// oracle_switchboard]` triples in any order. Every market with open size or
+/// resting orders except `current` must be passed, and so must the config of
+/// every listed mint with a balance; each account may appear only once.
+/// Market oracles must always match their market but are only read where
+/// the account holds size; collateral is always priced.
+pub fn load_portfolio(
+    margin: &MarginAccount,
+    current: &Pubkey,
+    remaining: &[AccountInfo],
+) -> Result<Portfolio> {
+    let triples = remaining.chunks_exact(3);
+    require!(
+        triples.remainder().is_empty(),
+        ErrorCode::MissingPortfolioMarket
+    );
+    let mut markets = Vec::with_capacity(triples.len());
+    let mut collateral_value = margin.collateral as u128;
+    let mut priced: Vec<Pubkey> = Vec::new();
+    let mut seen: Vec<Pubkey> = Vec::with_capacity(triples.len());
+    for triple in triples {
+        require_keys_eq!(
+            *triple[0].owner,
+            crate::ID,
+            ErrorCode::MissingPortfolioMarket
+        );
+        let key = triple[0].key();
+        require!(
+            key != *current && !seen.contains(&key),
+            ErrorCode::DuplicatePortfolioAccount
+        );
+        seen.push(key);
+        let data = triple[0].try_borrow_data()?;
+        if data.starts_with(CollateralConfig::DISCRIMINATOR) {
+            let config = CollateralConfig::try_deserialize(&mut &data[..])?;
+            require_keys_eq!(
+                triple[1].key(),
+                config.oracle_pyth,
+                ErrorCode::InvalidPriceFeed
+            );
+            require_keys_eq!(
+                triple[2].key(),
+                config.oracle_switchboard,
+                ErrorCode::InvalidPriceFeed
+            );
+            let amount = margin
+                .collateral_balances
+                .iter()
+                .find(|b| b.mint == config.mint)
+                .map_or(0, |b| b.amount);
+            if amount == 0 {
+                continue;
+            }
+            let (price, expo) = get_oracle_price(
+                &mut triple[1].clone(),
+                &mut triple[2].clone(),
+                config.oracle_max_age,
+                5,
+                3,
+            )?;
+            collateral_value = collateral_value
+                .checked_add(config.weighted_value(amount, price, expo)?)
+                .ok_or(error!(ErrorCode::Overflow))?;
+            priced.push(config.mint);
+            continue;
+        }
+        let market = Market::try_deserialize(&mut &data[..])?;
+        require_keys_eq!(
+            triple[1].key(),
+            market.oracle_pyth,
+            ErrorCode::InvalidPriceFeed
+        );
+        require_keys_eq!(
+            triple[2].key(),
+            market.oracle_switchboard,
+            ErrorCode::InvalidPriceFeed
+        );
+        let holds_size = margin
+            .positions
+            .iter()
+            .any(|p| p.market == key && p.qty > 0);
+        let mark_price = if holds_size {
+            Some(get_mark_price(
+                &market,
+                &mut triple[1].clone(),
+                &mut triple[2].clone(),
+                5,
+                3,
+            )?)
+        } else {
+            None
+        };
+        markets.push(PortfolioMarket {
+            key,
+            params: market.params,
+            mark_price,
+        });
+    }
+    for key in margin.active_markets() {
+        require!(
+            key == *current || markets.iter().any(|m| m.key == key),
+            ErrorCode::MissingPortfolioMarket
+        );
+    }
+    for balance in &margin.collateral_balances {
+        require!(
+            priced.contains(&balance.mint),
+            ErrorCode::MissingCollateralPrice
+        );
+    }
+    Ok(Portfolio {
+        markets,
+        collateral_value,
+        debt: margin.debt,
+    })
+}
+
+/// Initial margin check for an order of `notional` on `side` of `market`,
+/// applied to the account's post-trade exposure: an order against an
+/// existing position reduces it instead of adding to it. Resting orders are
+/// assumed to fill, and whichever side leaves the larger exposure is the one
+/// margined. In cross mode the equity, the collateral value with listed
+/// mints at their weight plus unrealized PnL everywhere, must also cover the
+/// initial margin of every other market the account trades; both are passed
+/// in `remaining`. `mark_price` prices this market and is required once the
+/// account holds size on it.
+///
+/// In hedge mode the legs do not offset each other, so the gross notional of
+/// both legs and of every opening order is margined, and closing orders are
+/// not checked at all. In isolated mode only the collateral posted on the
+/// position (or leg) the order trades and that position's PnL count,
+/// against that position alone.
+#[allow(clippy::too_many_arguments)]
+pub fn check_initial_margin(
+    margin: &MarginAccount,
+    params: &MarketParams,
+    market: &Pubkey,
+    side: Side,
+    notional: u128,
+    leg: Option<PositionLeg>,
+    mark_price: Option<i128>,
+    remaining: &[AccountInfo],
+) -> Result<()> {
+    if leg.is_some_and(|l| l.is_close()) {
+        return Ok(());
+    }
+    let (equity, required) = match margin.margin_type {
+        MarginType::Cross => {
+            let portfolio = load_portfolio(margin, market, remaining)?;
+            let own_pnl = if margin
+                .positions
+                .iter()
+                .any(|p| p.market == *market && p.qty > 0)
+            {
+                let mark_price = mark_price.ok_or(error!(ErrorCode::MissingOracle))?;
+                market_pnl(margin, market, mark_price)?
+            } else {
+                0
+            };
+            let others_pnl = others_pnl(margin, market, &portfolio.markets)?;
+            let equity = portfolio.equity(own_pnl.saturating_add(others_pnl))?;
+            (
+                equity,
+                portfolio_initial_margin(
+                    margin,
+                    params,
+                    market,
+                    side,
+                    notional,
+                    &portfolio.markets,
+                )?,
+            )
+        }
+        MarginType::Isolated => {
+            let pos_side = leg.map_or(side, |l| l.position_side());
+            let (collateral, pnl) = match margin.find_position(market, pos_side) {
+                Some(idx) if margin.positions[idx].qty > 0 => {
+                    let mark_price = mark_price.ok_or(error!(ErrorCode::MissingOracle))?;
+                    let pos = &margin.positions[idx];
+                    (pos.collateral, position_pnl(pos, mark_price)?)
+                }
+                Some(idx) => (margin.positions[idx].collateral, 0),
+                None => (0, 0),
+            };
+            let equity = (collateral as i128)
+                .checked_add(pnl)
+                .ok_or(error!(ErrorCode::Overflow))?;
+            let exposure = isolated_exposure(margin, market, pos_side, side, notional)?;
+            (equity, params.initial_margin(exposure))
+        }
+    };
+    require!(equity > 0, ErrorCode::InsufficientCollateral);
+    require!(required <= equity as u128, ErrorCode::LeverageExceeded);
+    Ok(())
+}
+
+/// Initial margin check run once a withdrawal has been debited: the
+/// remaining collateral value plus unrealized PnL on every market the
+/// account trades must still cover the portfolio's initial margin.
+/// Isolated positions carry their own collateral, so in isolated mode the
+/// account balances back nothing. The portfolio is only loaded from
+/// `remaining` when the account trades somewhere, so withdrawing from an
+/// idle account needs no oracles.
+pub fn check_withdrawal(margin: &MarginAccount, remaining: &[AccountInfo]) -> Result<()> {
+    if margin.margin_type == MarginType::Isolated || margin.active_markets().is_empty() {
+        return Ok(());
+    }
+    let portfolio = load_portfolio(margin, &Pubkey::default(), remaining)?;
+    let upnl = others_pnl(margin, &Pubkey::default(), &portfolio.markets)?;
+    let equity = portfolio.equity(upnl)?;
+    require!(equity > 0, ErrorCode::InsufficientCollateral);
+
+    let required = portfolio
+        .markets
+        .iter()
+        .try_fold(0u128, |acc, m| -> Result<u128> {
+            let exposure = exposure(margin, &m.key, Side::Bid, 0)?;
+            Ok(acc.saturating_add(m.params.initial_margin(exposure)))
+        })?;
+    require!(required <= equity as u128, ErrorCode::LeverageExceeded);
+    Ok(())
+}
+
+/// Whether a cross account's equity over every market it trades has fallen
+/// below the sum of their maintenance margins, at the ratio of the bracket
+/// each market's notional falls in
+pub fn is_below_maintenance(
+    margin: &MarginAccount,
+    params: &MarketParams,
+    market: &Pubkey,
+    mark_price: i128,
+    portfolio: &Portfolio,
+) -> Result<bool> {
+    let own_pnl = market_pnl(margin, market, mark_price)?;
+    let others_pnl = others_pnl(margin, market, &portfolio.markets)?;
+    let equity = portfolio.equity(own_pnl.saturating_add(others_pnl))?;
+    let maintenance = portfolio.markets.iter().filter(|m| m.key != *market).fold(
+        params.maintenance_margin(gross_notional(margin, market)),
+        |acc, m| acc.saturating_add(m.params.maintenance_margin(gross_notional(margin, &m.key))),
+    );
+    Ok(equity < 0 || (equity as u128) < maintenance)
+}
+
+/// Initial margin check for removing `amount` of isolated collateral from
+/// the position at `idx`
+fn check_isolated_removal(
+    margin: &MarginAccount,
+    params: &MarketParams,
+    idx: usize,
+    amount: u64,
+    mark_price: Option<i128>,
+) -> Result<()> {
+    let pos = &margin.positions[idx];
+    let remaining = pos
+        .collateral
+        .checked_sub(amount)
+        .ok_or(error!(ErrorCode::InsufficientCollateral))?;
+    let exposure = isolated_exposure(margin, &pos.market, pos.side, pos.side, 0)?;
+    if exposure == 0 {
+        return Ok(());
+    }
+
+    let upnl = if pos.qty > 0 {
+        let mark_price = mark_price.ok_or(error!(ErrorCode::MissingOracle))?;
+        position_pnl(pos, mark_price)?
+    } else {
+        0
+    };
+    let equity = (remaining as i128)
+        .checked_add(upnl)
+        .ok_or(error!(ErrorCode::Overflow))?;
+    require!(equity > 0, ErrorCode::InsufficientCollateral);
+    require!(
+        params.initial_margin(exposure) <= equity as u128,
+        ErrorCode::LeverageExceeded
+    );
+    Ok(())
+}
+
+/// Unrealized PnL of every position on `market` at `mark_price`
+pub fn market_pnl(margin: &MarginAccount, market: &Pubkey, mark_price: i128) -> Result<i128> {
+    margin
+        .positions
+        .iter()
+        .filter(|p| p.market == *market)
+        .try_fold(0i128, |acc, p| {
+            acc.checked_add(position_pnl(p, mark_price)?)
+                .ok_or(error!(ErrorCode::Overflow))
+        })
+}
+
+/// Unrealized PnL of one position at `mark_price`
+pub fn position_pnl(pos: &Position, mark_price: i128) -> Result<i128> {
+    let pnl = mark_price
+        .checked_sub(pos.entry_price as i128)
+        .and_then(|diff| diff.checked_mul(pos.qty as i128))
+        .ok_or(error!(ErrorCode::Overflow))?;
+    Ok(match pos.side {
+        Side::Bid => pnl,
+        Side::Ask => -pnl,
+    })
+}
+
+/// Unrealized PnL on every market in `others` except `current`
+fn others_pnl(
+    margin: &MarginAccount,
+    current: &Pubkey,
+    others: &[PortfolioMarket],
+) -> Result<i128> {
+    others
+        .iter()
+        .filter(|m| m.key != *current)
+        .filter(|m| {
+            margin
+                .positions
+                .iter()
+                .any(|p| p.market == m.key && p.qty > 0)
+        })
+        .try_fold(0i128, |acc, m| {
+            let mark_price = m.mark_price.ok_or(error!(ErrorCode::MissingOracle))?;
+            acc.checked_add(market_pnl(margin, &m.key, mark_price)?)
+                .ok_or(error!(ErrorCode::Overflow))
+        })
+}
+
+/// Initial margin of the whole portfolio once an order of `notional` on
+/// `side` of `market` is added
+fn portfolio_initial_margin(
+    margin: &MarginAccount,
+    params: &MarketParams,
+    market: &Pubkey,
+    side: Side,
+    notional: u128,
+    others: &[PortfolioMarket],
+) -> Result<u128> {
+    let own = params.initial_margin(exposure(margin, market, side, notional)?);
+    others
+        .iter()
+        .filter(|m| m.key != *market)
+        .try_fold(own, |acc, m| {
+            let exposure = exposure(margin, &m.key, Side::Bid, 0)?;
+            Ok(acc.saturating_add(m.params.initial_margin(exposure)))
+        })
+}
+
+/// Entry notional of every position on `market`, longs and shorts alike
+fn gross_notional(margin: &MarginAccount, market: &Pubkey) -> u128 {
+    margin
+        .positions
+        .iter()
+        .filter(|p| p.market == *market)
+        .fold(0u128, |acc, p| {
+            acc.saturating_add((p.entry_price as u128).saturating_mul(p.qty as u128))
+        })
+}
+
+/// Notional that must be margined on `market` once an order of `notional`
+/// on `side` is added to the account's positions and resting orders there
+fn exposure(margin: &MarginAccount, market: &Pubkey, side: Side, notional: u128) -> Result<u128> {
+    Ok(match margin.position_mode {
+        PositionMode::OneWay => one_way_exposure(margin, market, side, notional)?,
+        PositionMode::Hedge => margin
+            .positions
+            .iter()
+            .filter(|p| p.market == *market)
+            .try_fold(notional, |acc, p| {
+                (p.entry_price as u128)
+                    .checked_mul(p.qty as u128)
+                    .and_then(|n| acc.checked_add(n))
+                    .and_then(|n| n.checked_add(p.open_bid_notional as u128))
+                    .and_then(|n| n.checked_add(p.open_ask_notional as u128))
+            })
+            .ok_or(error!(ErrorCode::Overflow))?,
+    })
+}
+
+/// Notional margined by the isolated collateral of the position on
+/// `pos_side` of `market` once an order of `notional` on `side` is added.
+/// One-way accounts hold a single position per market, so this is the
+/// whole exposure there; a hedge leg only counts its own size and opening
+/// orders.
+fn isolated_exposure(
+    margin: &MarginAccount,
+    market: &Pubkey,
+    pos_side: Side,
+    side: Side,
+    notional: u128,
+) -> Result<u128> {
+    if margin.position_mode == PositionMode::OneWay {
+        return one_way_exposure(margin, market, side, notional);
+    }
+    let order = if side == pos_side { notional } else { 0 };
+    margin
+        .positions
+        .iter()
+        .filter(|p| p.market == *market && p.side == pos_side)
+        .try_fold(order, |acc, p| {
+            let open = match pos_side {
+                Side::Bid => p.open_bid_notional,
+                Side::Ask => p.open_ask_notional,
+            };
+            (p.entry_price as u128)
+                .checked_mul(p.qty as u128)
+                .and_then(|n| acc.checked_add(n))
+                .and_then(|n| n.checked_add(open as u128))
+        })
+        .ok_or(error!(ErrorCode::Overflow))
+}
+
+/// Worst-case net exposure of a one-way account on `market` after an order
+/// of `notional` on `side`
+fn one_way_exposure(
+    margin: &MarginAccount,
+    market: &Pubkey,
+    side: Side,
+    notional: u128,
+) -> Result<u128> {
+    let order = i128::try_from(notional).map_err(|_| error!(ErrorCode::Overflow))?;
+    let (order_bid, order_ask) = match side {
+        Side::Bid => (order, 0),
+        Side::Ask => (0, order),
+    };
+    let (mut net, mut bids, mut asks) = (0i128, order_bid, order_ask);
+    for p in margin.positions.iter().filter(|p| p.market == *market) {
+        let pos_notional = (p.entry_price as u128)
+            .checked_mul(p.qty as u128)
+            .and_then(|n| i128::try_from(n).ok())
+            .ok_or(error!(ErrorCode::Overflow))?;
+        net = match p.side {
+            Side::Bid => net.checked_add(pos_notional),
+            Side::Ask => net.checked_sub(pos_notional),
+        }
+        .ok_or(error!(ErrorCode::Overflow))?;
+        bids = bids
+            .checked_add(p.open_bid_notional as i128)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        asks = asks
+            .checked_add(p.open_ask_notional as i128)
+            .ok_or(error!(ErrorCode::Overflow))?;
+    }
+    net.checked_add(bids)
+        .zip(net.checked_sub(asks))
+        .map(|(long, short)| long.unsigned_abs().max(short.unsigned_abs()))
+        .ok_or(error!(ErrorCode::Overflow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::{account, market, params};
+
+    /// Backing storage for an `AccountInfo` passed in remaining accounts
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+    }
+
+    impl TestAccount {
+        fn new(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+            TestAccount {
+                key,
+                owner,
+                lamports: 0,
+                data,
+            }
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                false,
+                false,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            )
+        }
+    }
+
+    /// `[market, oracle_pyth, oracle_switchboard]` accounts of a market
+    /// stored under `key`
+    fn market_triple(key: Pubkey) -> [TestAccount; 3] {
+        let market = market(0, 6);
+        let mut data = Vec::new();
+        market.try_serialize(&mut data).unwrap();
+        [
+            TestAccount::new(key, crate::ID, data),
+            TestAccount::new(market.oracle_pyth, Pubkey::default(), Vec::new()),
+            TestAccount::new(market.oracle_switchboard, Pubkey::default(), Vec::new()),
+        ]
+    }
+
+    #[test]
+    fn portfolio_loads_idle_markets_without_oracles() {
+        let (current, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut m = account(MarginType::Cross, 100);
+        m.reserve_order(current, Side::Bid, 100).unwrap();
+        // the market being traded is never passed
+        assert!(load_portfolio(&m, &current, &[])
+            .unwrap()
+            .markets
+            .is_empty());
+
+        m.reserve_order(other, Side::Bid, 100).unwrap();
+        assert!(load_portfolio(&m, &current, &[]).is_err());
+        let [mut a, mut b, mut c] = market_triple(other);
+        let remaining = [a.info(), b.info(), c.info()];
+        let portfolio = load_portfolio(&m, &current, &remaining).unwrap();
+        assert_eq!(portfolio.markets.len(), 1);
+        assert_eq!(portfolio.markets[0].key, other);
+        assert_eq!(portfolio.markets[0].mark_price, None);
+        assert_eq!(portfolio.collateral_value, 100);
+    }
+
+    #[test]
+    fn portfolio_rejects_current_repeated_and_partial_entries() {
+        let (current, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let m = account(MarginType::Cross, 100);
+        let [mut a, mut b, mut c] = market_triple(current);
+        let remaining = [a.info(), b.info(), c.info()];
+        assert!(load_portfolio(&m, &current, &remaining).is_err());
+
+        let [mut a, mut b, mut c] = market_triple(other);
+        let [mut d, mut e, mut f] = market_triple(other);
+        let remaining = [a.info(), b.info(), c.info(), d.info(), e.info(), f.info()];
+        assert!(load_portfolio(&m, &current, &remaining).is_err());
+        assert!(load_portfolio(&m, &current, &remaining[..2]).is_err());
+        load_portfolio(&m, &current, &remaining[..3]).unwrap();
+    }
+
+    #[test]
+    fn cross_order_is_margined_on_collateral() {
+        let market = Pubkey::new_unique();
+        let params = params(&[]);
+        let m = account(MarginType::Cross, 50);
+        // 5% initial margin
+        check_initial_margin(&m, &params, &market, Side::Bid, 1_000, None, None, &[]).unwrap();
+        assert!(
+            check_initial_margin(&m, &params, &market, Side::Bid, 1_001, None, None, &[]).is_err()
+        );
+    }
+
+    #[test]
+    fn held_size_is_priced_at_the_mark() {
+        let market = Pubkey::new_unique();
+        let params = params(&[]);
+        let mut m = account(MarginType::Cross, 10);
+        m.apply_fill(market, Side::Bid, 100, 10, None, 0).unwrap();
+        let check =
+            |mark| check_initial_margin(&m, &params, &market, Side::Bid, 0, None, mark, &[]);
+        assert!(check(None).is_err());
+        // 5% of the held notional against 10 collateral plus the PnL
+        check(Some(105)).unwrap();
+        assert!(check(Some(103)).is_err());
+    }
+
+    #[test]
+    fn isolated_order_only_counts_its_position_collateral() {
+        let market = Pubkey::new_unique();
+        let params = params(&[]);
+        let mut m = account(MarginType::Isolated, 1_000);
+        let check = |m: &MarginAccount| {
+            check_initial_margin(m, &params, &market, Side::Bid, 1_000, None, None, &[])
+        };
+        assert!(check(&m).is_err());
+        let idx = m.find_or_open_position(market, Side::Bid).unwrap();
+        m.positions[idx].collateral = 50;
+        check(&m).unwrap();
+    }
+
+    #[test]
+    fn closing_leg_is_not_margined() {
+        let market = Pubkey::new_unique();
+        let m = account(MarginType::Cross, 0);
+        let leg = Some(PositionLeg::CloseLong);
+        check_initial_margin(&m, &params(&[]), &market, Side::Ask, 1_000, leg, None, &[]).unwrap();
+    }
+
+    #[test]
+    fn idle_account_withdraws_without_oracles() {
+        let mut m = account(MarginType::Cross, 100);
+        m.deposit_listed(Pubkey::new_unique(), 5).unwrap();
+        check_withdrawal(&m, &[]).unwrap();
+    }
+
+    #[test]
+    fn trading_account_must_pass_its_markets() {
+        let mut m = account(MarginType::Cross, 1_000);
+        m.reserve_order(Pubkey::new_unique(), Side::Bid, 100)
+            .unwrap();
+        assert!(check_withdrawal(&m, &[]).is_err());
+    }
+}
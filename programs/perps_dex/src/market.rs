@@ -1,6 +1,8 @@
 use anchor_lang::prelude::*;
+use anchor_spl::token_interface::Mint;
 
-use crate::state::{Market, MarketParams};
+use crate::errors::ErrorCode;
+use crate::state::{CollateralRegistry, Market, MarketParams};
 
 #[derive(Accounts)]
 #[instruction(market_nonce: u8, params: crate::state::MarketParams)]
@@ -10,13 +12,18 @@ pub struct InitializeMarket<'info> {
         seeds = [b"market", base_mint.key().as_ref(), quote_mint.key().as_ref(), &[market_nonce]],
         bump,
         payer = authority,
-        space = 8 + std::mem::size_of::<Market>()
+        space = 8 + Market::INIT_SPACE
     )]
     pub market: Account<'info, Market>,
-    /// CHECK: This is a token mint account
-    pub base_mint: AccountInfo<'info>,
-    /// CHECK: This is a token mint account
-    pub quote_mint: AccountInfo<'info>,
+    pub base_mint: InterfaceAccount<'info, Mint>,
+    #[account(address = registry.quote_mint @ ErrorCode::InvalidQuoteMint)]
+    pub quote_mint: InterfaceAccount<'info, Mint>,
+    /// CHECK: Pyth feed of the base asset, priced in the quote asset
+    pub oracle_pyth: UncheckedAccount<'info>,
+    /// CHECK: Switchboard feed of the base asset, priced in the quote asset
+    pub oracle_switchboard: UncheckedAccount<'info>,
+    #[account(seeds = [b"collateral_registry"], bump = registry.bump)]
+    pub registry: Account<'info, CollateralRegistry>,
     #[account(mut)]
     pub authority: Signer<'info>,
     pub system_program: Program<'info, System>,
@@ -27,13 +34,19 @@ pub fn initialize_market(
     market_nonce: u8,
     params: MarketParams,
 ) -> Result<()> {
+    params.validate()?;
     let m = &mut ctx.accounts.market;
     m.authority = *ctx.accounts.authority.key;
     m.base_mint = ctx.accounts.base_mint.key();
     m.quote_mint = ctx.accounts.quote_mint.key();
-    m.oracle_pyth = Pubkey::default();
-    m.oracle_switchboard = Pubkey::default();
+    m.base_decimals = ctx.accounts.base_mint.decimals;
+    m.quote_decimals = ctx.accounts.quote_mint.decimals;
+    m.oracle_pyth = ctx.accounts.oracle_pyth.key();
+    m.oracle_switchboard = ctx.accounts.oracle_switchboard.key();
+    m.event_queue = Pubkey::default();
     m.params = params;
     m.nonce = market_nonce;
+    m.fees_accrued = 0;
+    m.halted = false;
     Ok(())
 }
@@ -1,7 +1,12 @@
 use crate::errors::ErrorCode;
-use crate::slab::Slab;
-use crate::state::{EventQueue, MarginAccount, Market, OrderbookSide, Side};
-use crate::utils::push_event;
+use crate::event_queue::{EventQueue, EventQueueView, QueueEvent};
+use crate::events::{MarketOrderPlaced, OrderPlaced, TradeExecuted};
+use crate::margin::{check_initial_margin, validate_position_leg};
+use crate::slab::{order_key, Slab, SlabView};
+use crate::state::{
+    MarginAccount, Market, MarketParams, OrderType, OrderbookSide, PositionLeg, Side,
+};
+use crate::utils::get_optional_mark_price;
 use anchor_lang::prelude::*;
 use anchor_lang::AnchorDeserialize;
 
@@ -17,37 +22,88 @@ pub struct PlaceLimitOrder<'info> {
     pub orderbook_side: Account<'info, OrderbookSide>,
 
     /// Zero-copy slab buffer
+    #[account(mut, address = orderbook_side.slab)]
+    pub slab: AccountLoader<'info, Slab>,
+
+    /// Metadata for the side this order matches against
     #[account(
         mut,
-        seeds = [b"slab", orderbook_side.key().as_ref()],
-        bump
+        seeds = [b"orderbook", market.key().as_ref(), &[side.opposite() as u8]],
+        bump = opposite_orderbook_side.bump
     )]
-    pub slab: AccountLoader<'info, Slab>,
+    pub opposite_orderbook_side: Account<'info, OrderbookSide>,
+
+    /// Zero-copy slab buffer of the opposite side
+    #[account(mut, address = opposite_orderbook_side.slab)]
+    pub opposite_slab: AccountLoader<'info, Slab>,
 
     /// Event queue for orderbook events
+    #[account(mut, address = market.event_queue)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+
     #[account(
         mut,
-        seeds = [b"eventqueue", market.key().as_ref()],
-        bump = event_queue.bump
+        seeds = [b"margin", margin.owner.as_ref()],
+        bump = margin.bump,
+        constraint = margin.is_trader(&user.key()) @ ErrorCode::Unauthorized
     )]
-    pub event_queue: Account<'info, EventQueue>,
-
-    #[account(mut)]
     pub margin: Account<'info, MarginAccount>,
 
     #[account(mut)]
     pub user: Signer<'info>,
 
+    /// Accrues the taker fees of fills
+    #[account(mut, constraint = !market.halted @ ErrorCode::MarketHalted)]
     pub market: Account<'info, Market>,
 
-    pub token_program: Program<'info, anchor_spl::token::Token>,
+    /// CHECK: prices the margin check once the account holds size here
+    #[account(address = market.oracle_pyth)]
+    pub oracle_pyth: Option<AccountInfo<'info>>,
 
-    pub system_program: Program<'info, System>,
+    /// CHECK: prices the margin check once the account holds size here
+    #[account(address = market.oracle_switchboard)]
+    pub oracle_switchboard: Option<AccountInfo<'info>>,
 }
 
 #[derive(Accounts)]
-#[instruction(side: Side)]
+#[instruction(qty: u64, side: Side)]
 pub struct PlaceMarketOrder<'info> {
+    /// Metadata for the side this order takes liquidity from
+    #[account(
+        mut,
+        seeds = [b"orderbook", market.key().as_ref(), &[side.opposite() as u8]],
+        bump = opposite_orderbook_side.bump
+    )]
+    pub opposite_orderbook_side: Account<'info, OrderbookSide>,
+
+    #[account(mut, address = opposite_orderbook_side.slab)]
+    pub opposite_slab: AccountLoader<'info, Slab>,
+
+    #[account(mut, address = market.event_queue)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+
+    #[account(
+        mut,
+        seeds = [b"margin", margin.owner.as_ref()],
+        bump = margin.bump,
+        constraint = margin.is_trader(&user.key()) @ ErrorCode::Unauthorized
+    )]
+    pub margin: Account<'info, MarginAccount>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    /// Accrues the taker fees of fills
+    #[account(mut, constraint = !market.halted @ ErrorCode::MarketHalted)]
+    pub market: Account<'info, Market>,
+    /// CHECK: prices the margin check once the account holds size here
+    #[account(address = market.oracle_pyth)]
+    pub oracle_pyth: Option<AccountInfo<'info>>,
+    /// CHECK: prices the margin check once the account holds size here
+    #[account(address = market.oracle_switchboard)]
+    pub oracle_switchboard: Option<AccountInfo<'info>>,
+}
+#[derive(Accounts)]
+#[instruction(side: Side)]
+pub struct CancelOrder<'info> {
     #[account(
         mut,
         seeds = [b"orderbook", market.key().as_ref(), &[side as u8]],
@@ -55,72 +111,183 @@ pub struct PlaceMarketOrder<'info> {
     )]
     pub orderbook_side: Account<'info, OrderbookSide>,
 
+    #[account(mut, address = orderbook_side.slab)]
+    pub slab: AccountLoader<'info, Slab>,
+
+    #[account(mut, address = market.event_queue)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+
     #[account(
         mut,
-        seeds = [b"slab", orderbook_side.key().as_ref()],
-        bump
+        seeds = [b"margin", margin.owner.as_ref()],
+        bump = margin.bump,
+        constraint = margin.is_trader(&user.key()) @ ErrorCode::Unauthorized
     )]
-    pub slab: AccountLoader<'info, Slab>,
+    pub margin: Account<'info, MarginAccount>,
+
+    pub user: Signer<'info>,
+    pub market: Account<'info, Market>,
+}
 
+#[derive(Accounts)]
+pub struct CancelAllOrders<'info> {
     #[account(
         mut,
-        seeds = [b"eventqueue", market.key().as_ref()],
-        bump = event_queue.bump
+        seeds = [b"orderbook", market.key().as_ref(), &[Side::Bid as u8]],
+        bump = bids.bump
     )]
-    pub event_queue: Account<'info, EventQueue>,
+    pub bids: Account<'info, OrderbookSide>,
 
-    #[account(mut)]
+    #[account(mut, address = bids.slab)]
+    pub bid_slab: AccountLoader<'info, Slab>,
+
+    #[account(
+        mut,
+        seeds = [b"orderbook", market.key().as_ref(), &[Side::Ask as u8]],
+        bump = asks.bump
+    )]
+    pub asks: Account<'info, OrderbookSide>,
+
+    #[account(mut, address = asks.slab)]
+    pub ask_slab: AccountLoader<'info, Slab>,
+
+    #[account(mut, address = market.event_queue)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
+
+    #[account(
+        mut,
+        seeds = [b"margin", margin.owner.as_ref()],
+        bump = margin.bump,
+        constraint = margin.is_trader(&user.key()) @ ErrorCode::Unauthorized
+    )]
     pub margin: Account<'info, MarginAccount>,
-    #[account(mut)]
+
     pub user: Signer<'info>,
     pub market: Account<'info, Market>,
-    pub token_program: Program<'info, anchor_spl::token::Token>,
 }
+
 pub fn place_limit_order(
     ctx: Context<PlaceLimitOrder>,
     side: Side,
     price: u64,
     qty: u64,
+    order_type: OrderType,
+    client_order_id: Option<u64>,
+    position_leg: Option<PositionLeg>,
 ) -> Result<()> {
     let ob = &mut ctx.accounts.orderbook_side;
 
     msg!(
-        "Starting place_limit_order: side={:?}, price={}, qty={}",
+        "Starting place_limit_order: side={:?}, price={}, qty={}, type={:?}",
         side,
         price,
-        qty
+        qty,
+        order_type
     );
     require!(ob.side == side, ErrorCode::InvalidOrderbookSide);
 
-    let market = &ctx.accounts.market;
-    let margin = &ctx.accounts.margin;
+    let market_key = ctx.accounts.market.key();
+    let params = ctx.accounts.market.params.clone();
+    let mark_price = get_optional_mark_price(
+        &ctx.accounts.market,
+        ctx.accounts.oracle_pyth.as_ref(),
+        ctx.accounts.oracle_switchboard.as_ref(),
+    )?;
     let clock = Clock::get()?;
+    let mut queue = EventQueueView::load_mut(&ctx.accounts.event_queue)?;
+    let client_order_id = client_order_id.unwrap_or(0);
 
-    let collateral = margin.collateral as u128;
-    let lev_limit = market.params.leverage_limit as u128;
-    let order_notional = (price as u128)
-        .checked_mul(qty as u128)
-        .ok_or(error!(ErrorCode::Overflow))?;
+    // the book is kept in tick/lot units
+    let mut price_ticks = params.price_to_ticks(price)?;
+    let qty_lots = params.qty_to_lots(qty)?;
 
-    let existing_notional: u128 = margin.positions.iter().try_fold(0u128, |acc, p| {
-        (p.entry_price as u128)
-            .checked_mul(p.qty as u128)
-            .and_then(|notional| acc.checked_add(notional))
-            .ok_or(error!(ErrorCode::Overflow))
-    })?;
+    validate_position_leg(&ctx.accounts.margin, &market_key, side, qty, position_leg)?;
 
-    let total_notional = existing_notional
-        .checked_add(order_notional)
-        .ok_or(error!(ErrorCode::Overflow))?;
+    // take liquidity from the opposite book before resting anything
+    let (remaining, taker_fees) = {
+        let mut opposite = SlabView::load_mut(&ctx.accounts.opposite_slab)?;
+        price_ticks = post_only_price(&opposite, side, order_type, price_ticks)?;
 
-    require!(
-        total_notional <= collateral.saturating_mul(lev_limit),
-        ErrorCode::LeverageExceeded
-    );
+        // margin the order at the price it will actually rest at
+        let order_notional = (params.ticks_to_price(price_ticks)? as u128)
+            .checked_mul(qty as u128)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        check_initial_margin(
+            &ctx.accounts.margin,
+            &params,
+            &market_key,
+            side,
+            order_notional,
+            position_leg,
+            mark_price,
+            ctx.remaining_accounts,
+        )?;
+
+        let matched = match order_type {
+            OrderType::PostOnly | OrderType::PostOnlySlide => (qty_lots, 0),
+            OrderType::Limit | OrderType::ImmediateOrCancel | OrderType::FillOrKill => {
+                let taker = Taker {
+                    side,
+                    leg: position_leg,
+                    client_order_id,
+                };
+                match_orders(
+                    &mut opposite,
+                    &mut queue,
+                    &params,
+                    &mut ctx.accounts.margin,
+                    &taker,
+                    price_ticks,
+                    qty_lots,
+                )?
+            }
+        };
+        let opp_ob = &mut ctx.accounts.opposite_orderbook_side;
+        opp_ob.head = opposite.head;
+        opp_ob.free_head = opposite.free_head;
+        matched
+    };
+    let market = &mut ctx.accounts.market;
+    market.fees_accrued = market.fees_accrued.saturating_add(taker_fees);
+
+    let resting_lots = resting_lots(order_type, remaining)?;
+    let key = order_key(side, price_ticks, ob.next_order_id as u64);
+    let rests = resting_lots > 0;
+    emit!(OrderPlaced {
+        market: market_key,
+        owner: ctx.accounts.margin.owner,
+        side,
+        order_type,
+        price: params.ticks_to_price(price_ticks)?,
+        qty,
+        filled_qty: params.lots_to_qty(qty_lots - remaining)?,
+        resting_key: rests.then_some(key),
+        resting_qty: params.lots_to_qty(resting_lots)?,
+        client_order_id,
+    });
+    if remaining == 0 {
+        msg!("Limit order fully filled: price={}, qty={}", price, qty);
+        return Ok(());
+    }
+    if !rests {
+        msg!(
+            "IOC order done: filled={} lots, dropped={} lots",
+            qty_lots - remaining,
+            remaining
+        );
+        return Ok(());
+    }
 
-    let mut slab = ctx.accounts.slab.load_mut()?;
-    let key = ob.next_order_id as u128;
-    slab.insert(key, price, qty, ctx.accounts.user.key(), clock.slot)?;
+    let mut slab = SlabView::load_mut(&ctx.accounts.slab)?;
+    let leaf = slab.insert(
+        key,
+        price_ticks,
+        resting_lots,
+        ctx.accounts.margin.owner,
+        clock.slot,
+        client_order_id,
+    )?;
+    slab.nodes[leaf as usize].position_leg = PositionLeg::encode(position_leg);
 
     ob.next_order_id = ob
         .next_order_id
@@ -129,73 +296,607 @@ pub fn place_limit_order(
     ob.head = slab.head;
     ob.free_head = slab.free_head;
 
+    let resting_price = params.ticks_to_price(price_ticks)?;
+    let resting_qty = params.lots_to_qty(resting_lots)?;
+    // closing legs are reduce-only and need no margin of their own, but
+    // hold their size back from further closing orders
+    match position_leg {
+        Some(leg) if leg.is_close() => {
+            ctx.accounts
+                .margin
+                .reserve_close(&market_key, leg.position_side(), resting_qty)?
+        }
+        _ => ctx.accounts.margin.reserve_order(
+            market_key,
+            side,
+            resting_price as u128 * resting_qty as u128,
+        )?,
+    }
+    ctx.accounts
+        .margin
+        .add_open_order(market_key, side, key, client_order_id)?;
     msg!(
         "Placed limit order: key={}, price={}, qty={}",
         key,
-        price,
-        qty
+        resting_price,
+        resting_qty
     );
 
-    push_event(
-        &mut ctx.accounts.event_queue,
-        0,
+    queue.push(QueueEvent::Place {
+        owner: ctx.accounts.margin.owner,
+        side,
         key,
-        price,
-        qty,
-        ctx.accounts.user.key(),
-    )?;
+        price: resting_price,
+        qty: resting_qty,
+        client_order_id,
+        position_leg,
+    })?;
 
     Ok(())
 }
 
+/// Price in ticks a post-only order on `side` rests at: rejected when it
+/// would cross the `opposite` book, or slid one tick behind its best price
+/// for `PostOnlySlide`. Other order types keep `price_ticks`.
+fn post_only_price(
+    opposite: &SlabView,
+    side: Side,
+    order_type: OrderType,
+    price_ticks: u64,
+) -> Result<u64> {
+    let best_opposite = opposite
+        .best()
+        .map(|idx| opposite.nodes[idx as usize].price);
+    let crosses = best_opposite.is_some_and(|best| match side {
+        Side::Bid => price_ticks >= best,
+        Side::Ask => price_ticks <= best,
+    });
+    match (order_type, best_opposite) {
+        (OrderType::PostOnly, _) => {
+            require!(!crosses, ErrorCode::PostOnlyWouldCross);
+            Ok(price_ticks)
+        }
+        (OrderType::PostOnlySlide, Some(best)) if crosses => {
+            let slid = match side {
+                Side::Bid => best.checked_sub(1).filter(|p| *p > 0),
+                Side::Ask => best.checked_add(1),
+            }
+            .ok_or(error!(ErrorCode::PostOnlyWouldCross))?;
+            msg!("Post-only order slid to {} ticks", slid);
+            Ok(slid)
+        }
+        _ => Ok(price_ticks),
+    }
+}
+
+/// Lots of an unmatched remainder that go on the book: IOC drops them and
+/// FOK reverts unless nothing is left
+fn resting_lots(order_type: OrderType, remaining: u64) -> Result<u64> {
+    match order_type {
+        OrderType::ImmediateOrCancel => Ok(0),
+        OrderType::FillOrKill => {
+            require!(remaining == 0, ErrorCode::FillOrKillNotFilled);
+            Ok(0)
+        }
+        _ => Ok(remaining),
+    }
+}
+
+/// The order taking liquidity in `match_orders`
+struct Taker {
+    side: Side,
+    leg: Option<PositionLeg>,
+    client_order_id: u64,
+}
+
+/// Match up to `qty` lots against the opposite `slab`, best price first, while
+/// the resting price does not cross `limit_price` ticks. Fills execute at the
+/// maker's price. The taker side is netted into `margin` and charged its fee
+/// right away, so the account's next order or withdrawal already sees it; the
+/// fill is pushed to the event queue in native units for the maker side,
+/// tagged with both owners, the fees due on each side and the position legs
+/// of both orders. Returns the unfilled lots and the taker fees paid.
+fn match_orders(
+    slab: &mut SlabView,
+    queue: &mut EventQueueView,
+    params: &MarketParams,
+    margin: &mut MarginAccount,
+    taker: &Taker,
+    limit_price: u64,
+    qty: u64,
+) -> Result<(u64, u64)> {
+    let mut remaining = qty;
+    let mut fees = 0u64;
+    while remaining > 0 {
+        let Some(idx) = slab.best() else {
+            break;
+        };
+        let (key_node, price_node, qty0, owner_node, client_order_id, maker_leg) = {
+            let node_ref = &slab.nodes[idx as usize];
+            (
+                node_ref.key,
+                node_ref.price,
+                node_ref.qty,
+                node_ref.owner,
+                node_ref.client_order_id,
+                node_ref.position_leg,
+            )
+        };
+        let crosses = match taker.side {
+            Side::Bid => price_node <= limit_price,
+            Side::Ask => price_node >= limit_price,
+        };
+        if !crosses {
+            break;
+        }
+        let trade_qty = remaining.min(qty0);
+        let maker_out = trade_qty == qty0;
+        if maker_out {
+            slab.remove(idx)?;
+        } else {
+            slab.nodes[idx as usize].qty = qty0 - trade_qty;
+        }
+        let price = params.ticks_to_price(price_node)?;
+        let fill_qty = params.lots_to_qty(trade_qty)?;
+        let (maker_fee, taker_fee) = params.fill_fees(price as u128 * fill_qty as u128)?;
+        margin.apply_fill(
+            queue.market,
+            taker.side,
+            price,
+            fill_qty,
+            taker.leg,
+            taker_fee,
+        )?;
+        fees = fees.saturating_add(taker_fee);
+        queue.push(QueueEvent::Fill {
+            maker: owner_node,
+            taker: margin.owner,
+            taker_side: taker.side,
+            key: key_node,
+            price,
+            qty: fill_qty,
+            maker_client_order_id: client_order_id,
+            taker_client_order_id: taker.client_order_id,
+            maker_leg: PositionLeg::decode(maker_leg),
+            taker_leg: taker.leg,
+            maker_fee,
+            taker_fee,
+            maker_out,
+        })?;
+        emit!(TradeExecuted {
+            market: queue.market,
+            seq_num: queue.seq_num - 1,
+            maker: owner_node,
+            taker: margin.owner,
+            taker_side: taker.side,
+            maker_order_key: key_node,
+            price,
+            qty: fill_qty,
+            maker_fee,
+            taker_fee,
+        });
+        remaining -= trade_qty;
+    }
+    Ok((remaining, fees))
+}
+
 pub fn place_market_order(
     ctx: Context<PlaceMarketOrder>,
     qty: u64,
     side: Side,
     max_slippage_bps: u16,
+    position_leg: Option<PositionLeg>,
+    client_order_id: Option<u64>,
 ) -> Result<()> {
-    let ob = &mut ctx.accounts.orderbook_side;
-    require!(ob.side == side, ErrorCode::InvalidOrderbookSide);
-    let slab_ro = ctx.accounts.slab.load()?;
-    let best_idx = slab_ro.best().ok_or(error!(ErrorCode::OrderbookEmpty))?;
-    let best_price = slab_ro.nodes[best_idx as usize].price;
-    let allowed = best_price
-        .checked_mul(10_000u64.saturating_add(max_slippage_bps as u64))
+    let ob = &mut ctx.accounts.opposite_orderbook_side;
+    require!(ob.side == side.opposite(), ErrorCode::InvalidOrderbookSide);
+    let market_key = ctx.accounts.market.key();
+    let params = ctx.accounts.market.params.clone();
+    let mark_price = get_optional_mark_price(
+        &ctx.accounts.market,
+        ctx.accounts.oracle_pyth.as_ref(),
+        ctx.accounts.oracle_switchboard.as_ref(),
+    )?;
+    let qty_lots = params.qty_to_lots(qty)?;
+    let client_order_id = client_order_id.unwrap_or(0);
+    validate_position_leg(&ctx.accounts.margin, &market_key, side, qty, position_leg)?;
+
+    let mut slab = SlabView::load_mut(&ctx.accounts.opposite_slab)?;
+    let mut queue = EventQueueView::load_mut(&ctx.accounts.event_queue)?;
+    let best_idx = slab.best().ok_or(error!(ErrorCode::OrderbookEmpty))?;
+    let best_price = slab.nodes[best_idx as usize].price;
+
+    // worst acceptable price: a ceiling for buys, a floor for sells
+    let slippage_bps = match side {
+        Side::Bid => 10_000u64.saturating_add(max_slippage_bps as u64),
+        Side::Ask => 10_000u64.saturating_sub(max_slippage_bps as u64),
+    };
+    let allowed: u64 = (best_price as u128)
+        .checked_mul(slippage_bps as u128)
         .ok_or(error!(ErrorCode::Overflow))?
         .checked_div(10_000)
+        .ok_or(error!(ErrorCode::Overflow))?
+        .try_into()
+        .map_err(|_| error!(ErrorCode::Overflow))?;
+
+    // margin the expected fill before touching the book
+    let (_, fill_ticks_lots) = slab.quote(allowed, qty_lots);
+    let fill_notional = fill_ticks_lots
+        .checked_mul(params.tick_size as u128 * params.lot_size as u128)
         .ok_or(error!(ErrorCode::Overflow))?;
+    check_initial_margin(
+        &ctx.accounts.margin,
+        &params,
+        &market_key,
+        side,
+        fill_notional,
+        position_leg,
+        mark_price,
+        ctx.remaining_accounts,
+    )?;
 
-    let mut slab = ctx.accounts.slab.load_mut()?;
-    let mut remaining = qty;
-    while remaining > 0 {
-        if let Some(idx) = slab.best() {
-            // extract fields to avoid borrow conflicts
-            let (key_node, price_node, qty0, owner_node) = {
-                let node_ref = &slab.nodes[idx as usize];
-                (node_ref.key, node_ref.price, node_ref.qty, node_ref.owner)
-            };
-            require!(price_node <= allowed, ErrorCode::SlippageExceeded);
-            let trade_qty = remaining.min(qty0);
-            if trade_qty == qty0 {
-                slab.remove(idx)?;
-            } else {
-                slab.nodes[idx as usize].qty = qty0 - trade_qty;
-            }
-            push_event(
-                &mut ctx.accounts.event_queue,
-                1,
-                key_node,
-                price_node,
-                trade_qty,
-                owner_node,
-            )?;
-            remaining = remaining.saturating_sub(trade_qty);
-        } else {
-            break;
-        }
-    }
+    let taker = Taker {
+        side,
+        leg: position_leg,
+        client_order_id,
+    };
+    let (remaining, taker_fees) = match_orders(
+        &mut slab,
+        &mut queue,
+        &params,
+        &mut ctx.accounts.margin,
+        &taker,
+        allowed,
+        qty_lots,
+    )?;
+    // liquidity left beyond the bound means the order would have slipped
+    // further; an exhausted book just leaves the order partially filled
+    require!(
+        remaining == 0 || slab.best().is_none(),
+        ErrorCode::SlippageExceeded
+    );
+    let market = &mut ctx.accounts.market;
+    market.fees_accrued = market.fees_accrued.saturating_add(taker_fees);
+    emit!(MarketOrderPlaced {
+        market: market_key,
+        owner: ctx.accounts.margin.owner,
+        side,
+        limit_price: params.ticks_to_price(allowed)?,
+        qty,
+        filled_qty: params.lots_to_qty(qty_lots - remaining)?,
+        client_order_id,
+    });
 
     ob.head = slab.head;
     ob.free_head = slab.free_head;
     Ok(())
 }
+
+pub fn cancel_order(ctx: Context<CancelOrder>, side: Side, order_key: u128) -> Result<()> {
+    let owner = ctx.accounts.margin.owner;
+    let ob = &mut ctx.accounts.orderbook_side;
+    require!(ob.side == side, ErrorCode::InvalidOrderbookSide);
+
+    let mut slab = SlabView::load_mut(&ctx.accounts.slab)?;
+    let mut queue = EventQueueView::load_mut(&ctx.accounts.event_queue)?;
+    let idx = slab
+        .find(order_key)
+        .ok_or(error!(ErrorCode::OrderNotFound))?;
+    remove_order(
+        &mut slab,
+        &mut queue,
+        &mut ctx.accounts.margin,
+        &ctx.accounts.market,
+        side,
+        idx,
+        owner,
+    )?;
+    ob.head = slab.head;
+    ob.free_head = slab.free_head;
+
+    Ok(())
+}
+
+pub fn cancel_order_by_client_id(
+    ctx: Context<CancelOrder>,
+    side: Side,
+    client_order_id: u64,
+) -> Result<()> {
+    let ob = &mut ctx.accounts.orderbook_side;
+    require!(ob.side == side, ErrorCode::InvalidOrderbookSide);
+    require!(client_order_id != 0, ErrorCode::OrderNotFound);
+
+    let owner = ctx.accounts.margin.owner;
+    let market_key = ctx.accounts.market.key();
+    let mut slab = SlabView::load_mut(&ctx.accounts.slab)?;
+    let mut queue = EventQueueView::load_mut(&ctx.accounts.event_queue)?;
+    // the account's open orders locate the order without scanning the book;
+    // one already filled off it is skipped until the crank drops it
+    let idx = ctx
+        .accounts
+        .margin
+        .open_orders
+        .iter()
+        .filter(|o| {
+            o.market == market_key && o.side == side && o.client_order_id == client_order_id
+        })
+        .find_map(|o| slab.find(o.key))
+        .ok_or(error!(ErrorCode::OrderNotFound))?;
+    remove_order(
+        &mut slab,
+        &mut queue,
+        &mut ctx.accounts.margin,
+        &ctx.accounts.market,
+        side,
+        idx,
+        owner,
+    )?;
+    ob.head = slab.head;
+    ob.free_head = slab.free_head;
+
+    Ok(())
+}
+
+/// Unlink the order at `idx` after checking it belongs to `owner`, release
+/// the margin reserved for it, and push an out event for it.
+fn remove_order(
+    slab: &mut SlabView,
+    queue: &mut EventQueueView,
+    margin: &mut MarginAccount,
+    market: &Account<Market>,
+    side: Side,
+    idx: u32,
+    owner: Pubkey,
+) -> Result<()> {
+    let (key, price, qty, node_owner, client_order_id, position_leg) = {
+        let node = &slab.nodes[idx as usize];
+        (
+            node.key,
+            node.price,
+            node.qty,
+            node.owner,
+            node.client_order_id,
+            node.position_leg,
+        )
+    };
+    require_keys_eq!(node_owner, owner, ErrorCode::Unauthorized);
+
+    slab.remove(idx)?;
+
+    let price = market.params.ticks_to_price(price)?;
+    let qty = market.params.lots_to_qty(qty)?;
+    margin.release_resting(
+        &market.key(),
+        side,
+        price,
+        qty,
+        PositionLeg::decode(position_leg),
+    );
+    margin.remove_open_order(&market.key(), side, key);
+    msg!(
+        "Cancelled order: key={}, client_order_id={}, price={}, qty={}",
+        key,
+        client_order_id,
+        price,
+        qty
+    );
+
+    queue.push(QueueEvent::Out {
+        owner,
+        side,
+        key,
+        price,
+        qty,
+        client_order_id,
+        position_leg: PositionLeg::decode(position_leg),
+    })
+}
+
+/// Cancel every resting order of the account, optionally on one `side` only,
+/// removing at most `limit` orders so the call stays inside compute budget.
+pub fn cancel_all_orders(
+    ctx: Context<CancelAllOrders>,
+    side: Option<Side>,
+    limit: u8,
+) -> Result<()> {
+    let owner = ctx.accounts.margin.owner;
+    let mut budget = limit;
+    let mut queue = EventQueueView::load_mut(&ctx.accounts.event_queue)?;
+
+    if side != Some(Side::Ask) {
+        let mut slab = SlabView::load_mut(&ctx.accounts.bid_slab)?;
+        budget -= cancel_owner_orders(
+            &mut slab,
+            &mut queue,
+            &mut ctx.accounts.margin,
+            &ctx.accounts.market,
+            Side::Bid,
+            owner,
+            budget,
+        )?;
+        ctx.accounts.bids.head = slab.head;
+        ctx.accounts.bids.free_head = slab.free_head;
+    }
+    if side != Some(Side::Bid) {
+        let mut slab = SlabView::load_mut(&ctx.accounts.ask_slab)?;
+        budget -= cancel_owner_orders(
+            &mut slab,
+            &mut queue,
+            &mut ctx.accounts.margin,
+            &ctx.accounts.market,
+            Side::Ask,
+            owner,
+            budget,
+        )?;
+        ctx.accounts.asks.head = slab.head;
+        ctx.accounts.asks.free_head = slab.free_head;
+    }
+
+    msg!("Cancelled {} orders for {}", limit - budget, owner);
+    Ok(())
+}
+
+/// Remove up to `limit` of the account's open orders on `side` from `slab`,
+/// pushing an out event for each. Orders already filled off the book wait
+/// for the crank instead. Returns how many orders were removed.
+fn cancel_owner_orders(
+    slab: &mut SlabView,
+    queue: &mut EventQueueView,
+    margin: &mut MarginAccount,
+    market: &Account<Market>,
+    side: Side,
+    owner: Pubkey,
+    limit: u8,
+) -> Result<u8> {
+    let keys: Vec<u128> = margin
+        .open_orders
+        .iter()
+        .filter(|o| o.market == market.key() && o.side == side)
+        .map(|o| o.key)
+        .collect();
+    let mut removed = 0u8;
+    for key in keys {
+        if removed == limit {
+            break;
+        }
+        if let Some(idx) = slab.find(key) {
+            remove_order(slab, queue, margin, market, side, idx, owner)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_queue::tests::{stub_clock, with_queue};
+    use crate::slab::tests::with_slab;
+    use crate::state::tests::{account, params};
+    use crate::state::MarginType;
+
+    fn rest(slab: &mut SlabView, side: Side, price: u64, qty: u64, seq: u64) {
+        let maker = Pubkey::new_unique();
+        slab.insert(order_key(side, price, seq), price, qty, maker, 0, seq)
+            .unwrap();
+    }
+
+    #[test]
+    fn post_only_rejects_crossing_orders() {
+        with_slab(4, Side::Ask, |asks| {
+            rest(asks, Side::Ask, 100, 1, 0);
+            assert!(post_only_price(asks, Side::Bid, OrderType::PostOnly, 100).is_err());
+            assert_eq!(
+                post_only_price(asks, Side::Bid, OrderType::PostOnly, 99).unwrap(),
+                99
+            );
+            // other types keep their price and match instead
+            assert_eq!(
+                post_only_price(asks, Side::Bid, OrderType::Limit, 105).unwrap(),
+                105
+            );
+        });
+    }
+
+    #[test]
+    fn post_only_slide_rests_one_tick_behind_the_best_price() {
+        with_slab(4, Side::Ask, |asks| {
+            rest(asks, Side::Ask, 100, 1, 0);
+            let slide = OrderType::PostOnlySlide;
+            assert_eq!(post_only_price(asks, Side::Bid, slide, 105).unwrap(), 99);
+            assert_eq!(post_only_price(asks, Side::Bid, slide, 90).unwrap(), 90);
+        });
+        with_slab(4, Side::Bid, |bids| {
+            rest(bids, Side::Bid, 100, 1, 0);
+            let slide = OrderType::PostOnlySlide;
+            assert_eq!(post_only_price(bids, Side::Ask, slide, 95).unwrap(), 101);
+        });
+        with_slab(4, Side::Ask, |asks| {
+            // no price is left below a best ask of one tick
+            rest(asks, Side::Ask, 1, 1, 0);
+            assert!(post_only_price(asks, Side::Bid, OrderType::PostOnlySlide, 5).is_err());
+        });
+    }
+
+    #[test]
+    fn ioc_drops_and_fok_reverts_the_remainder() {
+        assert_eq!(resting_lots(OrderType::Limit, 3).unwrap(), 3);
+        assert_eq!(resting_lots(OrderType::ImmediateOrCancel, 3).unwrap(), 0);
+        assert_eq!(resting_lots(OrderType::FillOrKill, 0).unwrap(), 0);
+        assert!(resting_lots(OrderType::FillOrKill, 3).is_err());
+    }
+
+    #[test]
+    fn match_orders_fills_at_maker_prices_up_to_the_limit() {
+        stub_clock();
+        let mut p = params(&[]);
+        p.taker_fee_bps = 100;
+        let mut taker = account(MarginType::Cross, 1_000_000);
+        with_slab(4, Side::Ask, |asks| {
+            rest(asks, Side::Ask, 100, 2, 0);
+            rest(asks, Side::Ask, 101, 3, 1);
+            rest(asks, Side::Ask, 103, 4, 2);
+            with_queue(8, 8, |queue| {
+                let bid = Taker {
+                    side: Side::Bid,
+                    leg: None,
+                    client_order_id: 7,
+                };
+                let (remaining, fees) =
+                    match_orders(asks, queue, &p, &mut taker, &bid, 102, 6).unwrap();
+                // 2 @ 100 and 3 @ 101 fill, 103 is beyond the limit
+                assert_eq!(remaining, 1);
+                // 1% of 200 and of 303, each rounded down
+                assert_eq!(fees, 5);
+                assert_eq!(queue.count, 2);
+                let first = queue.peek().unwrap().unwrap();
+                let QueueEvent::Fill {
+                    price,
+                    qty,
+                    maker_client_order_id,
+                    taker_client_order_id,
+                    maker_out,
+                    ..
+                } = first.event
+                else {
+                    panic!("expected a fill");
+                };
+                assert_eq!((price, qty, maker_out), (100, 2, true));
+                assert_eq!((maker_client_order_id, taker_client_order_id), (0, 7));
+            });
+            assert_eq!(asks.leaf_count, 1);
+            assert_eq!(asks.nodes[asks.best().unwrap() as usize].price, 103);
+        });
+        let pos = &taker.positions[0];
+        assert_eq!((pos.side, pos.qty, pos.entry_price), (Side::Bid, 5, 100));
+    }
+
+    #[test]
+    fn match_orders_leaves_a_partly_filled_maker_on_the_book() {
+        stub_clock();
+        let p = params(&[]);
+        let mut taker = account(MarginType::Cross, 1_000_000);
+        with_slab(4, Side::Bid, |bids| {
+            rest(bids, Side::Bid, 100, 5, 0);
+            with_queue(8, 8, |queue| {
+                let ask = Taker {
+                    side: Side::Ask,
+                    leg: None,
+                    client_order_id: 7,
+                };
+                let (remaining, _) =
+                    match_orders(bids, queue, &p, &mut taker, &ask, 100, 2).unwrap();
+                assert_eq!(remaining, 0);
+                let record = queue.peek().unwrap().unwrap();
+                assert!(matches!(
+                    record.event,
+                    QueueEvent::Fill {
+                        maker_out: false,
+                        ..
+                    }
+                ));
+            });
+            assert_eq!(bids.nodes[bids.best().unwrap() as usize].qty, 3);
+        });
+    }
+}
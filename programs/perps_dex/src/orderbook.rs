@@ -1,12 +1,9 @@
 use crate::errors::ErrorCode;
-use crate::slab::Slab;
+use crate::slab::{Slab, SlabView};
 use crate::state::{Market, OrderbookSide, Side};
 use anchor_lang::prelude::*;
 use anchor_lang::AnchorDeserialize;
 
-pub const NULL_INDEX: u32 = u32::MAX;
-pub const MAX_SLAB_CAPACITY: usize = 140;
-
 #[derive(Accounts)]
 #[instruction(side: u8)]
 pub struct InitializeOrderbook<'info> {
@@ -20,17 +17,11 @@ pub struct InitializeOrderbook<'info> {
     )]
     pub orderbook_side: Account<'info, OrderbookSide>,
 
-    /// The zero-copy slab buffer
-    #[account(
-        init,
-        payer = authority,
-        seeds = [b"slab", orderbook_side.key().as_ref()],
-        bump,
-        // 8 byte discriminator + full Slab size
-        space = 8 + std::mem::size_of::<Slab>()
-    )]
+    /// Zero-copy slab buffer, created by the client with `Slab::space(capacity)`
+    #[account(zero)]
     pub slab: AccountLoader<'info, Slab>,
 
+    #[account(has_one = authority)]
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub authority: Signer<'info>,
@@ -47,10 +38,6 @@ pub fn initialize_orderbook(
         side,
         capacity
     );
-    require!(
-        (capacity as usize) > 0 && (capacity as usize) <= MAX_SLAB_CAPACITY,
-        ErrorCode::InvalidOrderbookCapacity
-    );
 
     // 1) Fill out your metadata account
     let ob = &mut ctx.accounts.orderbook_side;
@@ -60,11 +47,12 @@ pub fn initialize_orderbook(
         1 => Side::Ask,
         _ => return Err(error!(ErrorCode::InvalidOrderbookSide)),
     };
+    ob.slab = ctx.accounts.slab.key();
     ob.next_order_id = 1u128;
     ob.bump = ctx.bumps.orderbook_side;
 
     // 2) Initialize the slab in-place
-    let mut slab = ctx.accounts.slab.load_init()?;
+    let mut slab = SlabView::load_init(&ctx.accounts.slab)?;
     slab.init(capacity as usize, ob.side as u8)?;
 
     msg!("Initialized orderbook side: {:?}", ob.side);
@@ -72,8 +60,37 @@ pub fn initialize_orderbook(
         "Slab head={}, free_head={}, capacity={}",
         slab.head,
         slab.free_head,
-        capacity
+        slab.capacity
     );
 
     Ok(())
 }
+
+#[derive(Accounts)]
+pub struct CloseOrderbook<'info> {
+    #[account(
+        mut,
+        seeds = [b"orderbook", market.key().as_ref(), &[orderbook_side.side as u8]],
+        bump = orderbook_side.bump,
+        close = authority
+    )]
+    pub orderbook_side: Account<'info, OrderbookSide>,
+
+    #[account(mut, address = orderbook_side.slab, close = authority)]
+    pub slab: AccountLoader<'info, Slab>,
+
+    #[account(has_one = authority)]
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub authority: Signer<'info>,
+}
+
+/// Close one side of a market's book together with its slab, returning the
+/// rent to the market authority. Only allowed once the market is halted and
+/// no orders rest on it.
+pub fn close_orderbook(ctx: Context<CloseOrderbook>) -> Result<()> {
+    require!(ctx.accounts.market.halted, ErrorCode::MarketNotHalted);
+    let slab = ctx.accounts.slab.load()?;
+    require!(slab.leaf_count == 0, ErrorCode::OrderbookNotEmpty);
+    Ok(())
+}
@@ -1,49 +1,35 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Transfer};
 
 use crate::errors::ErrorCode;
-use crate::state::{OrderEvent, Position};
+use crate::event_queue::{EventQueue, EventQueueView, QueueEvent};
+use crate::events::FundingSettled;
 use crate::{
-    state::{EventQueue, MarginAccount, MarginType, Market, OrderbookSide, Side},
+    state::{MarginAccount, Market, PositionLeg, Side},
     utils::get_mark_price,
 };
 
 #[derive(Accounts)]
-pub struct SettleFills<'info> {
+pub struct ConsumeEvents<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
-    #[account(mut)]
-    pub event_queue: Account<'info, EventQueue>,
-    #[account(mut, constraint = market_vault.owner == market.key())]
-    pub market_vault: Account<'info, anchor_spl::token::TokenAccount>,
-    #[account(mut, seeds = [b"margin", market.key().as_ref(), maker.key().as_ref()], bump)]
-    pub maker_margin: Account<'info, MarginAccount>,
-    #[account(mut, constraint = maker_collateral.owner == maker_margin.owner)]
-    pub maker_collateral: Account<'info, anchor_spl::token::TokenAccount>,
-    #[account(mut, seeds = [b"margin", market.key().as_ref(), taker.key().as_ref()], bump)]
-    pub taker_margin: Account<'info, MarginAccount>,
-    #[account(mut, constraint = taker_collateral.owner == taker_margin.owner)]
-    pub taker_collateral: Account<'info, anchor_spl::token::TokenAccount>,
-    #[account(mut)]
-    pub orderbook_side: Account<'info, OrderbookSide>,
-
-    pub maker: Signer<'info>,
-    pub taker: Signer<'info>,
-    pub token_program: Program<'info, anchor_spl::token::Token>,
+    #[account(mut, address = market.event_queue)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
 }
 
 #[derive(Accounts)]
 pub struct SettleFunding<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
-    #[account(mut)]
+    #[account(mut, seeds = [b"margin", margin.owner.as_ref()], bump = margin.bump)]
     pub margin: Account<'info, MarginAccount>,
-    /// CHECK:
-    #[account(mut)]
+    /// CHECK: the market's Pyth feed
+    #[account(address = market.oracle_pyth)]
     pub oracle_pyth: AccountInfo<'info>,
-    /// CHECK:
-    #[account(mut)]
+    /// CHECK: the market's Switchboard feed
+    #[account(address = market.oracle_switchboard)]
     pub oracle_switchboard: AccountInfo<'info>,
+    #[account(mut, address = market.event_queue)]
+    pub event_queue: AccountLoader<'info, EventQueue>,
     pub clock: Sysvar<'info, Clock>,
 }
 
@@ -52,120 +38,249 @@ pub fn settle_funding(ctx: Context<SettleFunding>) -> Result<()> {
     let mut pyth_ai = ctx.accounts.oracle_pyth.to_account_info();
     let mut sb_ai = ctx.accounts.oracle_switchboard.to_account_info();
     let max_age = ctx.accounts.market.params.funding_interval;
-    let mark_price = get_mark_price(&mut pyth_ai, &mut sb_ai, max_age, 5, 3)?;
+    let mark_price = get_mark_price(&ctx.accounts.market, &mut pyth_ai, &mut sb_ai, 5, 3)?;
 
-    // branch by margin mode
+    // each account settles once per funding interval, and only while it
+    // holds size on this market
+    let market = ctx.accounts.market.key();
     let m = &mut ctx.accounts.margin;
-    match m.margin_type {
-        MarginType::Cross => {
-            let mut net: i128 = 0;
-            for pos in &m.positions {
-                let e = pos.entry_price as i128;
-                let diff = mark_price.saturating_sub(e);
-                let fund = diff
-                    .saturating_mul(pos.qty as i128)
-                    .checked_div(e)
-                    .unwrap_or(0);
-                net = match pos.side {
-                    Side::Bid => net.saturating_sub(fund),
-                    Side::Ask => net.saturating_add(fund),
-                };
-            }
-            if net < 0 {
-                let d = (-net) as u64;
-                require!(m.collateral >= d, ErrorCode::InsufficientCollateral);
-                m.collateral = m.collateral.saturating_sub(d);
-            } else {
-                m.collateral = m.collateral.saturating_add(net as u64);
-            }
-        }
-        MarginType::Isolated => {
-            for pos in &mut m.positions {
-                let e = pos.entry_price as i128;
-                let diff = mark_price.saturating_sub(e);
-                let fund = diff
-                    .saturating_mul(pos.qty as i128)
-                    .checked_div(e)
-                    .unwrap_or(0);
-                let delta = if pos.side == Side::Bid {
-                    (fund as i128).saturating_neg() as i64 as u64
-                } else {
-                    fund as u64
-                };
-                require!(pos.collateral >= delta, ErrorCode::InsufficientCollateral);
-                pos.collateral = pos.collateral.saturating_add(if pos.side == Side::Ask {
-                    delta
-                } else {
-                    delta.wrapping_neg()
-                });
-            }
-        }
+    let open: Vec<usize> = (0..m.positions.len())
+        .filter(|&i| m.positions[i].market == market && m.positions[i].qty > 0)
+        .collect();
+    require!(!open.is_empty(), ErrorCode::PositionNotFound);
+    let interval = i64::try_from(max_age).map_err(|_| error!(ErrorCode::Overflow))?;
+    for &i in &open {
+        let pos = &mut m.positions[i];
+        require!(
+            now.saturating_sub(pos.last_funding) >= interval,
+            ErrorCode::FundingNotDue
+        );
+        pos.last_funding = now;
     }
 
+    let payment = m.settle_funding(&market, mark_price)?;
+
+    let owner = m.owner;
+    EventQueueView::load_mut(&ctx.accounts.event_queue)?.push(QueueEvent::Funding {
+        owner,
+        mark_price,
+        payment,
+    })?;
+    emit!(FundingSettled {
+        market,
+        owner,
+        mark_price,
+        payment,
+    });
+
     // 4) update timestamp
     ctx.accounts.market.last_funding_timestamp = now;
     Ok(())
 }
 
-pub fn settle_fills(ctx: Context<SettleFills>) -> Result<()> {
-    const ORDER_EVENT_SIZE: usize = 65;
-    let queue = &mut ctx.accounts.event_queue;
-    while queue.head != queue.tail {
-        let idx = queue.head as usize;
-        let start = idx
-            .checked_mul(ORDER_EVENT_SIZE)
-            .ok_or(error!(ErrorCode::Overflow))?;
-        let end = start
-            .checked_add(ORDER_EVENT_SIZE)
-            .ok_or(error!(ErrorCode::Overflow))?;
-        require!(
-            end <= queue.events.len(),
-            ErrorCode::EventDeserializationFailure
-        );
-        let data = &queue.events[start..end];
-        let ev = OrderEvent::try_from_slice(data)
-            .map_err(|_| error!(ErrorCode::EventDeserializationFailure))?;
-        if ev.event_type == 1 {
-            let amt_u128 = (ev.price as u128)
-                .checked_mul(ev.qty as u128)
-                .ok_or(error!(ErrorCode::Overflow))?;
-            let amt: u64 = amt_u128
-                .try_into()
-                .map_err(|_| error!(ErrorCode::Overflow))?;
-            let cpi_accounts = Transfer {
-                from: ctx.accounts.taker_collateral.to_account_info(),
-                to: ctx.accounts.maker_collateral.to_account_info(),
-                authority: ctx.accounts.taker.to_account_info(),
-            };
-            let cpi_ctx =
-                CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
-            token::transfer(cpi_ctx, amt)?;
-            let maker_margin = &mut ctx.accounts.maker_margin;
-            if let Some(pos) = maker_margin.positions.iter_mut().find(|p| p.key == ev.key) {
-                pos.qty = pos.qty.saturating_sub(ev.qty);
+/// Permissionless crank: apply up to `limit` events from the front of the
+/// queue to the maker accounts they touch, passed writable in remaining
+/// accounts. Takers and liquidated accounts were already settled when the
+/// trade happened, so fills and liquidations only release the maker's
+/// reserved margin, net the fill into its position and charge its fee.
+/// Settlement is purely internal: realized PnL and fees move between
+/// `MarginAccount` balances and no tokens leave the market vault. Stops
+/// early, leaving the event queued, when a maker account was not passed.
+/// A fill the maker account cannot take is logged and dropped, so one bad
+/// event never stalls the queue.
+pub fn consume_events<'info>(
+    ctx: Context<'_, '_, 'info, 'info, ConsumeEvents<'info>>,
+    limit: u16,
+) -> Result<()> {
+    let mut margins: Vec<Account<'info, MarginAccount>> = Vec::new();
+    for ai in ctx.remaining_accounts {
+        let is_margin = ai.owner == &crate::ID
+            && ai.is_writable
+            && ai
+                .try_borrow_data()?
+                .starts_with(MarginAccount::DISCRIMINATOR);
+        // a duplicate would overwrite the first copy's changes on exit
+        if is_margin && !margins.iter().any(|m| m.key() == ai.key()) {
+            margins.push(Account::try_from(ai)?);
+        }
+    }
+    let find = |margins: &[Account<MarginAccount>], owner: &Pubkey| {
+        margins.iter().position(|m| m.owner == *owner)
+    };
+
+    let market = ctx.accounts.market.key();
+    let mut fees: u64 = 0;
+    let mut queue = EventQueueView::load_mut(&ctx.accounts.event_queue)?;
+    let mut consumed = 0u16;
+    while consumed < limit {
+        let Some(record) = queue.peek()? else {
+            break;
+        };
+        match record.event {
+            QueueEvent::Fill {
+                maker: maker_key,
+                taker_side,
+                key,
+                price,
+                qty,
+                maker_leg,
+                maker_fee,
+                maker_out,
+                ..
+            } => {
+                let Some(maker) = find(&margins, &maker_key) else {
+                    msg!(
+                        "Missing margin account for event {}, stopping",
+                        record.seq_num
+                    );
+                    break;
+                };
+                let fill = MakerFill {
+                    side: taker_side.opposite(),
+                    key,
+                    price,
+                    qty,
+                    leg: maker_leg,
+                    fee: maker_fee,
+                    out: maker_out,
+                };
+                let fee = apply_maker_fill(&mut margins[maker], market, &fill, record.seq_num);
+                fees = fees.saturating_add(fee);
             }
-            let taker_margin = &mut ctx.accounts.taker_margin;
-            let taker_side = if ctx.accounts.orderbook_side.side == Side::Bid {
-                Side::Ask
-            } else {
-                Side::Bid
-            };
-            if let Some(pos) = taker_margin.positions.iter_mut().find(|p| p.key == ev.key) {
-                pos.qty = pos.qty.saturating_add(ev.qty);
-            } else {
-                taker_margin.positions.push(Position {
-                    key: ev.key,
-                    qty: ev.qty,
-                    entry_price: ev.price,
-                    side: taker_side,
-                    collateral: 0,
-                });
+            QueueEvent::Liquidation {
+                maker: maker_key,
+                maker_side,
+                key,
+                price,
+                qty,
+                maker_leg,
+                maker_out,
+                ..
+            } => {
+                let Some(maker) = find(&margins, &maker_key) else {
+                    msg!(
+                        "Missing margin account for event {}, stopping",
+                        record.seq_num
+                    );
+                    break;
+                };
+                let fill = MakerFill {
+                    side: maker_side,
+                    key,
+                    price,
+                    qty,
+                    leg: maker_leg,
+                    fee: 0,
+                    out: maker_out,
+                };
+                apply_maker_fill(&mut margins[maker], market, &fill, record.seq_num);
             }
+            QueueEvent::Place { .. } | QueueEvent::Out { .. } | QueueEvent::Funding { .. } => {}
         }
-        queue.head = queue.head.wrapping_add(1);
+        queue.pop();
+        consumed += 1;
     }
 
-    ctx.accounts.maker_margin.positions.retain(|p| p.qty > 0);
-    ctx.accounts.taker_margin.positions.retain(|p| p.qty > 0);
+    for margin in &margins {
+        margin.exit(&crate::ID)?;
+    }
+    let market = &mut ctx.accounts.market;
+    market.fees_accrued = market.fees_accrued.saturating_add(fees);
+    msg!("Consumed {} events", consumed);
     Ok(())
 }
+
+/// The maker's side of a fill or liquidation event
+struct MakerFill {
+    side: Side,
+    key: u128,
+    price: u64,
+    qty: u64,
+    leg: Option<PositionLeg>,
+    fee: u64,
+    /// The fill emptied the resting order
+    out: bool,
+}
+
+/// Apply the maker's side of event `seq_num` to `margin` and return the fee
+/// charged. What the resting order held is always released; if netting the
+/// fill fails the position is left as it was, the failure is logged and no
+/// fee is charged, since the taker side has already settled.
+fn apply_maker_fill(
+    margin: &mut MarginAccount,
+    market: Pubkey,
+    fill: &MakerFill,
+    seq_num: u64,
+) -> u64 {
+    margin.release_resting(&market, fill.side, fill.price, fill.qty, fill.leg);
+    if fill.out {
+        margin.remove_open_order(&market, fill.side, fill.key);
+    }
+    let mut applied = margin.clone();
+    match applied.apply_fill(market, fill.side, fill.price, fill.qty, fill.leg, fill.fee) {
+        Ok(_) => {
+            *margin = applied;
+            fill.fee
+        }
+        Err(err) => {
+            msg!("Dropping event {} for {}: {:?}", seq_num, margin.owner, err);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::account;
+    use crate::state::MarginType;
+
+    fn bid(qty: u64, fee: u64, out: bool) -> MakerFill {
+        MakerFill {
+            side: Side::Bid,
+            key: 7,
+            price: 100,
+            qty,
+            leg: None,
+            fee,
+            out,
+        }
+    }
+
+    #[test]
+    fn maker_fill_releases_the_order_and_nets_the_position() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Cross, 1_000);
+        m.reserve_order(market, Side::Bid, 300).unwrap();
+        m.add_open_order(market, Side::Bid, 7, 0).unwrap();
+
+        // a partial fill keeps the order tracked
+        assert_eq!(apply_maker_fill(&mut m, market, &bid(1, 2, false), 0), 2);
+        assert_eq!(m.open_orders.len(), 1);
+        assert_eq!(m.positions[0].open_bid_notional, 200);
+
+        assert_eq!(apply_maker_fill(&mut m, market, &bid(2, 3, true), 1), 3);
+        assert!(m.open_orders.is_empty());
+        let pos = &m.positions[0];
+        assert_eq!((pos.open_bid_notional, pos.qty), (0, 3));
+        assert_eq!(m.collateral, 995);
+    }
+
+    #[test]
+    fn failed_maker_fill_is_dropped() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Cross, 1_000);
+        m.apply_fill(market, Side::Bid, 1, u64::MAX, None, 0)
+            .unwrap();
+        m.reserve_order(market, Side::Bid, 100).unwrap();
+        m.add_open_order(market, Side::Bid, 7, 0).unwrap();
+
+        // netting overflows the position, yet the order still leaves the
+        // book and no fee is taken
+        assert_eq!(apply_maker_fill(&mut m, market, &bid(1, 5, true), 0), 0);
+        assert!(m.open_orders.is_empty());
+        let pos = &m.positions[0];
+        assert_eq!((pos.open_bid_notional, pos.qty), (0, u64::MAX));
+        assert_eq!(m.collateral, 1_000);
+    }
+}
@@ -1,58 +1,140 @@
 use crate::errors::ErrorCode;
 use crate::state::Side;
+use crate::utils::load_with_tail_mut;
 use anchor_lang::prelude::*;
+use std::cell::RefMut;
+use std::ops::{Deref, DerefMut};
 
 pub const NULL_INDEX: u32 = u32::MAX;
-pub const MAX_SLAB_CAPACITY: usize = 140;
 
-/// Zero-copy slab node for in-place mutation\認
+pub const FREE_NODE: u32 = 0;
+pub const INNER_NODE: u32 = 1;
+pub const LEAF_NODE: u32 = 2;
+
+/// Zero-copy slab node for in-place mutation.
+/// Leaves hold resting orders; inner nodes only use `key`, `prefix_len` and
+/// `children`. Free nodes are chained through `children[0]`.
 
 #[account(zero_copy)]
 #[repr(C)]
 pub struct SlabNode {
-    pub key: u128,          // 16 bytes
-    pub price: u64,         // 8 bytes
-    pub qty: u64,           // 8 bytes
-    pub owner: Pubkey,      // 32 bytes
-    pub inserted_slot: u64, // 8 bytes
-    pub next: u32,          // index or NULL_INDEX
-    pub prev: u32,          // index or NULL_INDEX
+    pub key: u128,            // 16 bytes: order key, or shared prefix for inner nodes
+    pub price: u64,           // 8 bytes
+    pub qty: u64,             // 8 bytes
+    pub owner: Pubkey,        // 32 bytes
+    pub inserted_slot: u64,   // 8 bytes
+    pub client_order_id: u64, // 8 bytes, 0 when unset
+    pub tag: u32,             // FREE_NODE, INNER_NODE or LEAF_NODE
+    pub prefix_len: u32,      // inner: leading key bits shared by both children
+    pub children: [u32; 2],   // inner: child indexes; free: next free node
+    pub position_leg: u8,     // encoded PositionLeg, 0 when unset
+    pub _padding: [u8; 15],   // pad to 16-byte alignment
 }
 
-/// Zero-copy slab structure stored on-chain\認
+/// Zero-copy slab header stored on-chain, followed in the account by as
+/// many `SlabNode`s as the account holds.
+/// Orders are kept in a critbit tree keyed by `order_key`, so the best order
+/// is the smallest key for asks and the largest key for bids.
 
 #[account(zero_copy)]
 #[repr(C)]
 pub struct Slab {
-    pub head: u32,                            // 4 bytes
-    pub free_head: u32,                       // 4 bytes
-    pub side: u8,                             // 1 byte: 0 = Bid, 1 = Ask
-    pub _padding: [u8; 7],                    // pad to 16-byte alignment
-    pub nodes: [SlabNode; MAX_SLAB_CAPACITY], // fixed array of nodes
+    pub head: u32,          // root index or NULL_INDEX
+    pub free_head: u32,     // first free node or NULL_INDEX
+    pub leaf_count: u32,    // resting orders
+    pub best_leaf: u32,     // cached best order or NULL_INDEX
+    pub capacity: u32,      // max resting orders
+    pub side: u8,           // 1 byte: 0 = Bid, 1 = Ask
+    pub _padding: [u8; 11], // pad to 16-byte alignment
 }
 
 impl Slab {
-    /// Initialize the free list and side
+    /// Account size for a slab holding `capacity` orders: a critbit tree
+    /// over n leaves needs n - 1 inner nodes
+    pub fn space(capacity: usize) -> usize {
+        8 + std::mem::size_of::<Slab>() + (capacity * 2 - 1) * std::mem::size_of::<SlabNode>()
+    }
+}
+
+/// A slab header and the node array behind it, borrowed mutably from the
+/// slab account
+pub struct SlabView<'a> {
+    pub header: RefMut<'a, Slab>,
+    pub nodes: RefMut<'a, [SlabNode]>,
+}
+
+impl Deref for SlabView<'_> {
+    type Target = Slab;
+
+    fn deref(&self) -> &Slab {
+        &self.header
+    }
+}
+
+impl DerefMut for SlabView<'_> {
+    fn deref_mut(&mut self) -> &mut Slab {
+        &mut self.header
+    }
+}
+
+/// Critbit key of an order: price in the high 64 bits, then the sequence
+/// number. Bids invert the sequence so that, at equal price, the earlier order
+/// has the larger key and keeps time priority.
+pub fn order_key(side: Side, price: u64, seq: u64) -> u128 {
+    let seq = match side {
+        Side::Bid => !seq,
+        Side::Ask => seq,
+    };
+    ((price as u128) << 64) | seq as u128
+}
+
+/// Bit of `key` at position `bit`, counted from the most significant bit
+fn key_bit(key: u128, bit: u32) -> usize {
+    ((key >> (127 - bit)) & 1) as usize
+}
+
+impl<'a> SlabView<'a> {
+    /// Borrow an initialized slab account
+    pub fn load_mut(loader: &'a AccountLoader<'_, Slab>) -> Result<Self> {
+        let (header, nodes) = load_with_tail_mut(loader, false)?;
+        Ok(Self { header, nodes })
+    }
+
+    /// Borrow a freshly created slab account for `init`
+    pub fn load_init(loader: &'a AccountLoader<'_, Slab>) -> Result<Self> {
+        let (header, nodes) = load_with_tail_mut(loader, true)?;
+        Ok(Self { header, nodes })
+    }
+
+    /// Initialize the free list and side. The account must hold the
+    /// `capacity * 2 - 1` nodes a tree of `capacity` orders needs.
     pub fn init(&mut self, capacity: usize, side: u8) -> Result<()> {
         require!(
-            capacity > 0 && capacity <= MAX_SLAB_CAPACITY,
+            capacity > 0 && capacity * 2 - 1 <= self.nodes.len(),
             ErrorCode::InvalidOrderbookCapacity
         );
-        for i in 0..capacity {
-            self.nodes[i].next = if i + 1 < capacity {
-                (i + 1) as u32
-            } else {
-                NULL_INDEX
-            };
-            self.nodes[i].prev = NULL_INDEX;
+        let node_count = capacity * 2 - 1;
+        for i in 0..node_count {
+            self.nodes[i].tag = FREE_NODE;
+            self.nodes[i].children = [
+                if i + 1 < node_count {
+                    (i + 1) as u32
+                } else {
+                    NULL_INDEX
+                },
+                NULL_INDEX,
+            ];
         }
-        self.head = NULL_INDEX;
-        self.free_head = 0;
-        self.side = side;
+        self.header.head = NULL_INDEX;
+        self.header.free_head = 0;
+        self.header.leaf_count = 0;
+        self.header.best_leaf = NULL_INDEX;
+        self.header.capacity = capacity as u32;
+        self.header.side = side;
         Ok(())
     }
 
-    /// Insert a new order in sorted order, mutating in-place
+    /// Insert a new order under its critbit `key`, mutating in-place
     pub fn insert(
         &mut self,
         key: u128,
@@ -60,82 +142,358 @@ impl Slab {
         qty: u64,
         owner: Pubkey,
         slot: u64,
+        client_order_id: u64,
     ) -> Result<u32> {
         require!(qty > 0, ErrorCode::InvalidQuantity);
-        let idx = self.free_head;
-        require!(idx != NULL_INDEX, ErrorCode::OrderbookFull);
-        let i = idx as usize;
-        self.free_head = self.nodes[i].next;
-
-        let mut curr = self.head;
-        let mut prev = NULL_INDEX;
-        while curr != NULL_INDEX {
-            let node = &self.nodes[curr as usize];
-            let should = if self.side == Side::Bid as u8 {
-                price > node.price || (price == node.price && slot < node.inserted_slot)
-            } else {
-                price < node.price || (price == node.price && slot < node.inserted_slot)
-            };
-            if should {
-                break;
-            }
-            prev = curr;
-            curr = node.next;
-        }
+        require!(
+            self.header.leaf_count < self.header.capacity,
+            ErrorCode::OrderbookFull
+        );
 
-        let node = &mut self.nodes[i];
+        let leaf = self.alloc()?;
+        let node = &mut self.nodes[leaf as usize];
+        node.tag = LEAF_NODE;
         node.key = key;
         node.price = price;
         node.qty = qty;
         node.owner = owner;
         node.inserted_slot = slot;
-        node.prev = prev;
-        node.next = curr;
+        node.client_order_id = client_order_id;
+        node.position_leg = 0;
+        node.children = [NULL_INDEX; 2];
 
-        if prev != NULL_INDEX {
-            self.nodes[prev as usize].next = idx;
+        if self.header.head == NULL_INDEX {
+            self.header.head = leaf;
+            self.header.leaf_count = 1;
+            self.header.best_leaf = leaf;
+            return Ok(leaf);
+        }
+
+        // the closest existing key decides where the new branch splits off
+        let closest = self.nodes[self.find_closest(key) as usize].key;
+        require!(closest != key, ErrorCode::DuplicateOrderKey);
+        let crit = (closest ^ key).leading_zeros();
+
+        let mut parent = NULL_INDEX;
+        let mut parent_dir = 0;
+        let mut curr = self.header.head;
+        while self.nodes[curr as usize].tag == INNER_NODE
+            && self.nodes[curr as usize].prefix_len < crit
+        {
+            parent = curr;
+            parent_dir = key_bit(key, self.nodes[curr as usize].prefix_len);
+            curr = self.nodes[curr as usize].children[parent_dir];
+        }
+
+        let inner = self.alloc()?;
+        let dir = key_bit(key, crit);
+        let node = &mut self.nodes[inner as usize];
+        node.tag = INNER_NODE;
+        node.key = key;
+        node.prefix_len = crit;
+        node.children[dir] = leaf;
+        node.children[1 - dir] = curr;
+
+        if parent == NULL_INDEX {
+            self.header.head = inner;
         } else {
-            self.head = idx;
+            self.nodes[parent as usize].children[parent_dir] = inner;
         }
-        if curr != NULL_INDEX {
-            self.nodes[curr as usize].prev = idx;
+        self.header.leaf_count += 1;
+
+        if self.is_better(key, self.nodes[self.header.best_leaf as usize].key) {
+            self.header.best_leaf = leaf;
         }
-        Ok(idx)
+        Ok(leaf)
     }
 
-    /// Remove a node by index, unlink and free
+    /// Remove a leaf by index, unlink it and its parent, and free both
     pub fn remove(&mut self, idx: u32) -> Result<()> {
         let i = idx as usize;
-        require!(i < MAX_SLAB_CAPACITY, ErrorCode::InvalidIndex);
-        let prev = self.nodes[i].prev;
-        let next = self.nodes[i].next;
-        if prev != NULL_INDEX {
-            self.nodes[prev as usize].next = next;
+        require!(
+            i < self.nodes.len() && self.nodes[i].tag == LEAF_NODE,
+            ErrorCode::InvalidIndex
+        );
+        let key = self.nodes[i].key;
+
+        if self.header.head == idx {
+            self.header.head = NULL_INDEX;
+        } else {
+            let mut grandparent = NULL_INDEX;
+            let mut grandparent_dir = 0;
+            let mut parent = self.header.head;
+            loop {
+                let node = &self.nodes[parent as usize];
+                require!(node.tag == INNER_NODE, ErrorCode::InvalidSlabData);
+                let dir = key_bit(key, node.prefix_len);
+                let child = node.children[dir];
+                if child == idx {
+                    let sibling = node.children[1 - dir];
+                    if grandparent == NULL_INDEX {
+                        self.header.head = sibling;
+                    } else {
+                        self.nodes[grandparent as usize].children[grandparent_dir] = sibling;
+                    }
+                    self.free(parent);
+                    break;
+                }
+                grandparent = parent;
+                grandparent_dir = dir;
+                parent = child;
+            }
+        }
+
+        self.free(idx);
+        self.header.leaf_count -= 1;
+        if self.header.best_leaf == idx {
+            self.header.best_leaf = self.find_best();
+        }
+        Ok(())
+    }
+
+    /// Return index of the active order with `key`
+    pub fn find(&self, key: u128) -> Option<u32> {
+        if self.header.head == NULL_INDEX {
+            return None;
+        }
+        let idx = self.find_closest(key);
+        if self.nodes[idx as usize].key == key {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+
+    /// Return index of best active order
+    pub fn best(&self) -> Option<u32> {
+        if self.header.best_leaf == NULL_INDEX {
+            None
+        } else {
+            Some(self.header.best_leaf)
+        }
+    }
+
+    /// Walk resting orders best first and return the lots a taker could fill,
+    /// up to `qty`, at prices no worse than `limit_price`, together with the
+    /// filled notional in ticks * lots
+    pub fn quote(&self, limit_price: u64, qty: u64) -> (u64, u128) {
+        let is_bid = self.header.side == Side::Bid as u8;
+        let first = if is_bid { 1 } else { 0 };
+        let mut filled = 0u64;
+        let mut notional = 0u128;
+        // inner prefixes strictly grow along a path, so depth is bounded by the key width
+        let mut stack = [NULL_INDEX; 130];
+        let mut len = 0;
+        if self.header.head != NULL_INDEX {
+            stack[0] = self.header.head;
+            len = 1;
+        }
+        while len > 0 && filled < qty {
+            len -= 1;
+            let node = &self.nodes[stack[len] as usize];
+            if node.tag == INNER_NODE {
+                stack[len] = node.children[1 - first];
+                stack[len + 1] = node.children[first];
+                len += 2;
+                continue;
+            }
+            let crosses = if is_bid {
+                node.price >= limit_price
+            } else {
+                node.price <= limit_price
+            };
+            if !crosses {
+                break;
+            }
+            let take = node.qty.min(qty - filled);
+            filled += take;
+            notional = notional.saturating_add(take as u128 * node.price as u128);
+        }
+        (filled, notional)
+    }
+
+    /// Walk from the root towards `key` and return the leaf reached
+    fn find_closest(&self, key: u128) -> u32 {
+        let mut curr = self.header.head;
+        while self.nodes[curr as usize].tag == INNER_NODE {
+            let node = &self.nodes[curr as usize];
+            curr = node.children[key_bit(key, node.prefix_len)];
+        }
+        curr
+    }
+
+    /// Walk the tree down its best edge: smallest key for asks, largest for bids
+    fn find_best(&self) -> u32 {
+        if self.header.head == NULL_INDEX {
+            return NULL_INDEX;
+        }
+        let dir = if self.header.side == Side::Bid as u8 {
+            1
         } else {
-            self.head = next;
+            0
+        };
+        let mut curr = self.header.head;
+        while self.nodes[curr as usize].tag == INNER_NODE {
+            curr = self.nodes[curr as usize].children[dir];
         }
-        if next != NULL_INDEX {
-            self.nodes[next as usize].prev = prev;
+        curr
+    }
+
+    fn is_better(&self, key: u128, other: u128) -> bool {
+        if self.header.side == Side::Bid as u8 {
+            key > other
+        } else {
+            key < other
         }
-        let node = &mut self.nodes[i];
+    }
+
+    fn alloc(&mut self) -> Result<u32> {
+        let idx = self.header.free_head;
+        require!(idx != NULL_INDEX, ErrorCode::OrderbookFull);
+        self.header.free_head = self.nodes[idx as usize].children[0];
+        Ok(idx)
+    }
+
+    fn free(&mut self, idx: u32) {
+        let node = &mut self.nodes[idx as usize];
+        node.tag = FREE_NODE;
         node.key = 0;
         node.price = 0;
         node.qty = 0;
         node.owner = Pubkey::default();
         node.inserted_slot = 0;
-        node.prev = NULL_INDEX;
+        node.client_order_id = 0;
+        node.prefix_len = 0;
+        node.position_leg = 0;
         // prepend to free list
-        node.next = self.free_head;
-        self.free_head = idx;
-        Ok(())
+        node.children = [self.header.free_head, NULL_INDEX];
+        self.header.free_head = idx;
     }
+}
 
-    /// Return index of best active order
-    pub fn best(&self) -> Option<u32> {
-        if self.head == NULL_INDEX {
-            None
-        } else {
-            Some(self.head)
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    pub(crate) fn with_slab(capacity: usize, side: Side, f: impl FnOnce(&mut SlabView)) {
+        let header = RefCell::new(<Slab as bytemuck::Zeroable>::zeroed());
+        let nodes = RefCell::new(vec![
+            <SlabNode as bytemuck::Zeroable>::zeroed();
+            capacity * 2 - 1
+        ]);
+        let mut slab = SlabView {
+            header: header.borrow_mut(),
+            nodes: RefMut::map(nodes.borrow_mut(), |n| n.as_mut_slice()),
+        };
+        slab.init(capacity, side as u8).unwrap();
+        f(&mut slab);
+    }
+
+    fn place(slab: &mut SlabView, side: Side, price: u64, seq: u64) -> u32 {
+        slab.insert(
+            order_key(side, price, seq),
+            price,
+            1,
+            Pubkey::default(),
+            0,
+            seq,
+        )
+        .unwrap()
+    }
+
+    fn best_price(slab: &SlabView) -> Option<u64> {
+        slab.best().map(|idx| slab.nodes[idx as usize].price)
+    }
+
+    #[test]
+    fn best_ask_is_lowest_price() {
+        with_slab(8, Side::Ask, |slab| {
+            for (seq, price) in [105, 101, 110, 103].into_iter().enumerate() {
+                place(slab, Side::Ask, price, seq as u64);
+            }
+            assert_eq!(best_price(slab), Some(101));
+            assert_eq!(slab.leaf_count, 4);
+        });
+    }
+
+    #[test]
+    fn best_bid_is_highest_price() {
+        with_slab(8, Side::Bid, |slab| {
+            for (seq, price) in [95, 99, 90, 97].into_iter().enumerate() {
+                place(slab, Side::Bid, price, seq as u64);
+            }
+            assert_eq!(best_price(slab), Some(99));
+        });
+    }
+
+    #[test]
+    fn earlier_order_keeps_priority_at_equal_price() {
+        for side in [Side::Bid, Side::Ask] {
+            with_slab(8, side, |slab| {
+                let first = place(slab, side, 100, 1);
+                place(slab, side, 100, 2);
+                assert_eq!(slab.best(), Some(first));
+            });
         }
     }
+
+    #[test]
+    fn removing_the_best_order_promotes_the_next() {
+        with_slab(8, Side::Ask, |slab| {
+            let best = place(slab, Side::Ask, 100, 1);
+            let next = place(slab, Side::Ask, 101, 2);
+            place(slab, Side::Ask, 102, 3);
+            slab.remove(best).unwrap();
+            assert_eq!(slab.best(), Some(next));
+            assert_eq!(slab.find(order_key(Side::Ask, 100, 1)), None);
+            assert_eq!(slab.leaf_count, 2);
+        });
+    }
+
+    #[test]
+    fn freed_nodes_are_reused_until_the_book_is_full() {
+        with_slab(2, Side::Ask, |slab| {
+            let a = place(slab, Side::Ask, 100, 1);
+            place(slab, Side::Ask, 101, 2);
+            assert!(slab
+                .insert(
+                    order_key(Side::Ask, 102, 3),
+                    102,
+                    1,
+                    Pubkey::default(),
+                    0,
+                    3
+                )
+                .is_err());
+            slab.remove(a).unwrap();
+            place(slab, Side::Ask, 99, 4);
+            assert_eq!(best_price(slab), Some(99));
+            slab.remove(slab.best().unwrap()).unwrap();
+            slab.remove(slab.best().unwrap()).unwrap();
+            assert_eq!(slab.best(), None);
+            assert_eq!(slab.head, NULL_INDEX);
+        });
+    }
+
+    #[test]
+    fn init_rejects_capacity_beyond_the_account() {
+        let header = RefCell::new(<Slab as bytemuck::Zeroable>::zeroed());
+        let nodes = RefCell::new(vec![<SlabNode as bytemuck::Zeroable>::zeroed(); 3]);
+        let mut slab = SlabView {
+            header: header.borrow_mut(),
+            nodes: RefMut::map(nodes.borrow_mut(), |n| n.as_mut_slice()),
+        };
+        assert!(slab.init(3, Side::Bid as u8).is_err());
+        assert!(slab.init(2, Side::Bid as u8).is_ok());
+    }
+
+    #[test]
+    fn space_fits_the_tree() {
+        assert_eq!(std::mem::size_of::<Slab>(), 32);
+        assert_eq!(
+            Slab::space(10),
+            8 + 32 + 19 * std::mem::size_of::<SlabNode>()
+        );
+    }
 }
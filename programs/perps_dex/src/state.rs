@@ -1,87 +1,881 @@
 use anchor_lang::prelude::*;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+use crate::errors::ErrorCode;
+
+/// Basis points in one whole
+pub const BPS: u128 = 10_000;
+/// Most margin brackets a market can define
+pub const MAX_MARGIN_BRACKETS: usize = 8;
+
+/// Margin ratios for positions whose notional reaches `min_notional`
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Clone)]
+pub struct MarginBracket {
+    pub min_notional: u64,
+    pub initial_margin_bps: u16,
+    pub maintenance_margin_bps: u16,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Clone)]
 pub struct MarketParams {
     pub tick_size: u64,
     pub lot_size: u64,
-    pub leverage_limit: u8,
+    /// Initial margin below the first bracket; 800 bps is 12.5x leverage
+    pub initial_margin_bps: u16,
     pub funding_interval: u64,
-    pub maintenance_margin_ratio: u16,
+    /// Maintenance margin below the first bracket
+    pub maintenance_margin_bps: u16,
+    /// Brackets by ascending `min_notional`; larger positions get stricter ratios
+    #[max_len(MAX_MARGIN_BRACKETS)]
+    pub margin_brackets: Vec<MarginBracket>,
+    /// Fee on the notional of resting orders that get filled
+    pub maker_fee_bps: u16,
+    /// Fee on the notional of orders that take liquidity
+    pub taker_fee_bps: u16,
+}
+
+impl MarketParams {
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            self.tick_size > 0 && self.lot_size > 0,
+            ErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.maintenance_margin_bps > 0
+                && self.maintenance_margin_bps <= self.initial_margin_bps
+                && self.initial_margin_bps as u128 <= BPS,
+            ErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.margin_brackets.len() <= MAX_MARGIN_BRACKETS,
+            ErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.maker_fee_bps as u128 <= BPS && self.taker_fee_bps as u128 <= BPS,
+            ErrorCode::InvalidMarketParams
+        );
+        let mut prev = MarginBracket {
+            min_notional: 0,
+            initial_margin_bps: self.initial_margin_bps,
+            maintenance_margin_bps: self.maintenance_margin_bps,
+        };
+        for bracket in &self.margin_brackets {
+            require!(
+                bracket.min_notional > prev.min_notional
+                    && bracket.initial_margin_bps >= prev.initial_margin_bps
+                    && bracket.maintenance_margin_bps >= prev.maintenance_margin_bps
+                    && bracket.maintenance_margin_bps <= bracket.initial_margin_bps
+                    && bracket.initial_margin_bps as u128 <= BPS,
+                ErrorCode::InvalidMarketParams
+            );
+            prev = bracket.clone();
+        }
+        Ok(())
+    }
+
+    /// Initial and maintenance margin ratios in bps for a position of `notional`
+    pub fn margin_ratios(&self, notional: u128) -> (u16, u16) {
+        self.margin_brackets
+            .iter()
+            .rev()
+            .find(|b| notional >= b.min_notional as u128)
+            .map_or(
+                (self.initial_margin_bps, self.maintenance_margin_bps),
+                |b| (b.initial_margin_bps, b.maintenance_margin_bps),
+            )
+    }
+
+    /// Collateral required to open or hold `notional`
+    pub fn initial_margin(&self, notional: u128) -> u128 {
+        let (initial, _) = self.margin_ratios(notional);
+        notional.saturating_mul(initial as u128).div_ceil(BPS)
+    }
+
+    /// Equity below which a position of `notional` can be liquidated
+    pub fn maintenance_margin(&self, notional: u128) -> u128 {
+        let (_, maintenance) = self.margin_ratios(notional);
+        notional.saturating_mul(maintenance as u128).div_ceil(BPS)
+    }
+
+    /// Maker and taker fees on a fill of `notional`
+    pub fn fill_fees(&self, notional: u128) -> Result<(u64, u64)> {
+        let fee = |bps: u16| {
+            u64::try_from(notional.saturating_mul(bps as u128) / BPS)
+                .map_err(|_| error!(ErrorCode::Overflow))
+        };
+        Ok((fee(self.maker_fee_bps)?, fee(self.taker_fee_bps)?))
+    }
+
+    /// Native price to book ticks; the price must sit on the tick grid
+    pub fn price_to_ticks(&self, price: u64) -> Result<u64> {
+        require!(
+            price > 0 && price.checked_rem(self.tick_size) == Some(0),
+            ErrorCode::InvalidTickSize
+        );
+        Ok(price / self.tick_size)
+    }
+
+    /// Native quantity to book lots; the quantity must be a whole number of lots
+    pub fn qty_to_lots(&self, qty: u64) -> Result<u64> {
+        require!(
+            qty > 0 && qty.checked_rem(self.lot_size) == Some(0),
+            ErrorCode::InvalidLotSize
+        );
+        Ok(qty / self.lot_size)
+    }
+
+    pub fn ticks_to_price(&self, ticks: u64) -> Result<u64> {
+        ticks
+            .checked_mul(self.tick_size)
+            .ok_or(error!(ErrorCode::Overflow))
+    }
+
+    pub fn lots_to_qty(&self, lots: u64) -> Result<u64> {
+        lots.checked_mul(self.lot_size)
+            .ok_or(error!(ErrorCode::Overflow))
+    }
 }
 
 #[account]
+#[derive(InitSpace)]
 pub struct Market {
     pub authority: Pubkey,
     pub base_mint: Pubkey,
     pub quote_mint: Pubkey,
+    pub base_decimals: u8,
+    pub quote_decimals: u8,
 
     pub oracle_pyth: Pubkey,
     pub oracle_switchboard: Pubkey,
+    /// Zero-copy event queue, set by `initialize_event_queue`
+    pub event_queue: Pubkey,
     pub params: MarketParams,
     pub nonce: u8,
     pub last_funding_timestamp: i64,
     pub cumulative_funding_rate: i128,
+    /// Trading fees collected from margin accounts, held in the quote vault
+    pub fees_accrued: u64,
+    /// Set by `halt_market`: no new orders are accepted, and the books and
+    /// event queue may be closed once drained
+    pub halted: bool,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, PartialEq, Eq, Copy)]
+impl Market {
+    /// Book price, in native quote per native base unit, of an oracle price
+    /// of `price * 10^expo` quote per whole base token
+    pub fn book_price(&self, price: i128, expo: i32) -> Result<i128> {
+        let shift = expo + self.quote_decimals as i32 - self.base_decimals as i32;
+        let factor = 10i128
+            .checked_pow(shift.unsigned_abs())
+            .ok_or(error!(ErrorCode::Overflow))?;
+        if shift >= 0 {
+            price.checked_mul(factor).ok_or(error!(ErrorCode::Overflow))
+        } else {
+            Ok(price / factor)
+        }
+    }
+}
+
+/// Most non-quote collateral mints a margin account can hold
+pub const MAX_COLLATERALS: usize = 4;
+
+/// Discount on oracle value at which listed collateral is sold to a
+/// liquidator repaying the account's debt
+pub const COLLATERAL_LIQUIDATION_BONUS_BPS: u16 = 500;
+
+/// Program-wide collateral setup: the quote mint every market settles in,
+/// the vault holding all quote collateral, and the admin who lists other mints
+#[account]
+#[derive(InitSpace)]
+pub struct CollateralRegistry {
+    pub authority: Pubkey,
+    pub quote_mint: Pubkey,
+    pub quote_vault: Pubkey,
+    pub quote_decimals: u8,
+    pub bump: u8,
+}
+
+/// A mint accepted as margin collateral next to the quote asset
+#[account]
+#[derive(InitSpace)]
+pub struct CollateralConfig {
+    pub mint: Pubkey,
+    pub vault: Pubkey,
+    pub oracle_pyth: Pubkey,
+    pub oracle_switchboard: Pubkey,
+    pub decimals: u8,
+    /// Decimals of the quote mint balances are valued in
+    pub quote_decimals: u8,
+    /// Share of the oracle value that counts towards equity, in bps
+    pub weight_bps: u16,
+    /// Most of the mint the vault accepts, in native units
+    pub deposit_cap: u64,
+    pub total_deposits: u64,
+    pub oracle_max_age: u64,
+    pub bump: u8,
+}
+
+impl CollateralConfig {
+    /// Weighted value in native quote units of `amount` native units, at an
+    /// oracle price of `price * 10^expo` quote per whole token
+    pub fn weighted_value(&self, amount: u64, price: i128, expo: i32) -> Result<u128> {
+        if price <= 0 {
+            return Ok(0);
+        }
+        let shift = expo + self.quote_decimals as i32 - self.decimals as i32;
+        let factor = 10u128
+            .checked_pow(shift.unsigned_abs())
+            .ok_or(error!(ErrorCode::Overflow))?;
+        let value = (amount as u128)
+            .checked_mul(price as u128)
+            .and_then(|v| v.checked_mul(self.weight_bps as u128))
+            .ok_or(error!(ErrorCode::Overflow))?;
+        if shift >= 0 {
+            value
+                .checked_mul(factor)
+                .map(|v| v / BPS)
+                .ok_or(error!(ErrorCode::Overflow))
+        } else {
+            Ok(value / BPS / factor)
+        }
+    }
+
+    /// Native units worth `value` native quote at an oracle price of
+    /// `price * 10^expo` quote per whole token, plus `bonus_bps` on top
+    pub fn amount_for_value(
+        &self,
+        value: u64,
+        price: i128,
+        expo: i32,
+        bonus_bps: u16,
+    ) -> Result<u64> {
+        require!(price > 0, ErrorCode::InvalidPriceFeed);
+        let shift = expo + self.quote_decimals as i32 - self.decimals as i32;
+        let factor = 10u128
+            .checked_pow(shift.unsigned_abs())
+            .ok_or(error!(ErrorCode::Overflow))?;
+        let value = (value as u128)
+            .checked_mul(BPS + bonus_bps as u128)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        let per_token = (price as u128)
+            .checked_mul(BPS)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        let amount = if shift >= 0 {
+            per_token.checked_mul(factor).map(|p| value / p)
+        } else {
+            value.checked_mul(factor).map(|v| v / per_token)
+        }
+        .ok_or(error!(ErrorCode::Overflow))?;
+        u64::try_from(amount).map_err(|_| error!(ErrorCode::Overflow))
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Debug, Clone, PartialEq, Eq, Copy)]
 pub enum Side {
     Bid = 0,
     Ask = 1,
 }
 
+impl Side {
+    /// The side a taker on `self` matches against
+    pub fn opposite(&self) -> Side {
+        match self {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        }
+    }
+}
+
+/// Time-in-force of a limit order
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, PartialEq, Eq, Copy)]
+pub enum OrderType {
+    /// Good-til-cancelled: take what crosses, rest the remainder
+    Limit,
+    /// Never take liquidity; rejected if it would cross
+    PostOnly,
+    /// Never take liquidity; repriced one tick behind the best opposite order
+    PostOnlySlide,
+    /// Take what crosses, drop the remainder
+    ImmediateOrCancel,
+    /// Fill the whole quantity or revert
+    FillOrKill,
+}
+
+/// How fills on one market net into positions
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Debug, Clone, PartialEq, Eq, Copy)]
+pub enum PositionMode {
+    /// A single net position per market; opposite fills reduce and flip it
+    OneWay,
+    /// Separate long and short legs per market, each opened and closed explicitly
+    Hedge,
+}
+
+/// Position leg an order trades in hedge mode
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, PartialEq, Eq, Copy)]
+pub enum PositionLeg {
+    OpenLong,
+    CloseLong,
+    OpenShort,
+    CloseShort,
+}
+
+impl PositionLeg {
+    /// Book side an order on this leg is placed on
+    pub fn order_side(&self) -> Side {
+        match self {
+            PositionLeg::OpenLong | PositionLeg::CloseShort => Side::Bid,
+            PositionLeg::CloseLong | PositionLeg::OpenShort => Side::Ask,
+        }
+    }
+
+    /// Side of the position this leg trades
+    pub fn position_side(&self) -> Side {
+        match self {
+            PositionLeg::OpenLong | PositionLeg::CloseLong => Side::Bid,
+            PositionLeg::OpenShort | PositionLeg::CloseShort => Side::Ask,
+        }
+    }
+
+    /// Closing legs are reduce-only
+    pub fn is_close(&self) -> bool {
+        matches!(self, PositionLeg::CloseLong | PositionLeg::CloseShort)
+    }
+
+    /// Leg an order on `side` opens
+    pub fn opening(side: Side) -> PositionLeg {
+        match side {
+            Side::Bid => PositionLeg::OpenLong,
+            Side::Ask => PositionLeg::OpenShort,
+        }
+    }
+
+    /// Slab and event queue encoding; 0 when the order has no leg
+    pub fn encode(leg: Option<PositionLeg>) -> u8 {
+        leg.map_or(0, |l| l as u8 + 1)
+    }
+
+    pub fn decode(value: u8) -> Option<PositionLeg> {
+        match value {
+            1 => Some(PositionLeg::OpenLong),
+            2 => Some(PositionLeg::CloseLong),
+            3 => Some(PositionLeg::OpenShort),
+            4 => Some(PositionLeg::CloseShort),
+            _ => None,
+        }
+    }
+}
+
 #[account]
 pub struct OrderbookSide {
     pub market: Pubkey,
     pub side: Side,
     pub head: u32,
     pub free_head: u32,
-    pub slab: Vec<u8>,
+    pub slab: Pubkey,
     pub next_order_id: u128,
     pub bump: u8,
 }
 
-#[account]
-pub struct EventQueue {
-    pub market: Pubkey,
-    pub head: u32,
-    pub tail: u32,
-    pub events: Vec<u8>,
-    pub bump: u8,
-}
-
-
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Debug, Clone, PartialEq, Eq, Copy)]
 pub enum MarginType {
     Cross,
     Isolated,
 }
 
-#[derive(AnchorDeserialize, AnchorSerialize, Clone)]
-#[repr(C)]
-pub struct OrderEvent {
-    pub event_type: u8,
-    pub key: u128,
-    pub price: u64,
-    pub qty: u64,
-    pub owner: Pubkey,
-}
+/// Most positions a margin account can hold across all markets
+pub const MAX_POSITIONS: usize = 16;
 
+/// Most resting orders a margin account can hold across all markets
+pub const MAX_OPEN_ORDERS: usize = 32;
+
+/// Portfolio account of one trader, shared by every market: the quote
+/// collateral balance and the weighted value of any listed collateral back
+/// positions and resting orders on all of them. PnL and fees settle in the
+/// quote balance.
 #[account]
+#[derive(InitSpace)]
 pub struct MarginAccount {
     pub owner: Pubkey,
+    /// Hot key allowed to place and cancel orders for the owner
+    pub delegate: Option<Pubkey>,
     pub collateral: u64,
+    /// Quote owed once losses or fees exceeded the balance; anything
+    /// credited repays it first, so it is only non-zero at a zero balance
+    pub debt: u64,
+    /// Balances of listed non-quote collateral mints
+    #[max_len(MAX_COLLATERALS)]
+    pub collateral_balances: Vec<CollateralBalance>,
     pub margin_type: MarginType,
+    pub position_mode: PositionMode,
+    /// One net position per market, or one per leg in hedge mode
+    #[max_len(MAX_POSITIONS)]
     pub positions: Vec<Position>,
+    /// Resting orders, kept until they are cancelled or their last fill is
+    /// consumed
+    #[max_len(MAX_OPEN_ORDERS)]
+    pub open_orders: Vec<OpenOrder>,
     pub bump: u8,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
-pub struct Position {
+impl MarginAccount {
+    /// Whether `signer` may trade the account: the owner or its delegate
+    pub fn is_trader(&self, signer: &Pubkey) -> bool {
+        *signer == self.owner || self.delegate == Some(*signer)
+    }
+
+    /// Reserve margin for a resting order of `notional` on `side` of `market`.
+    /// In hedge mode only opening orders reserve, so bids land on the long
+    /// leg and asks on the short leg.
+    pub fn reserve_order(&mut self, market: Pubkey, side: Side, notional: u128) -> Result<()> {
+        let notional: u64 = notional
+            .try_into()
+            .map_err(|_| error!(ErrorCode::Overflow))?;
+        let idx = self.find_or_open_position(market, side)?;
+        let open = self.positions[idx].open_notional_mut(side);
+        *open = open
+            .checked_add(notional)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+
+    /// Release margin reserved for a resting order once it fills or is cancelled
+    pub fn release_order(&mut self, market: &Pubkey, side: Side, notional: u128) {
+        let notional = u64::try_from(notional).unwrap_or(u64::MAX);
+        if let Some(idx) = self.find_position(market, side) {
+            let open = self.positions[idx].open_notional_mut(side);
+            *open = open.saturating_sub(notional);
+        }
+    }
+
+    /// Credit `amount` of quote, repaying any debt first
+    pub fn credit(&mut self, amount: u64) -> Result<()> {
+        let repaid = amount.min(self.debt);
+        self.debt -= repaid;
+        self.collateral = self
+            .collateral
+            .checked_add(amount - repaid)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+
+    /// Debit `amount` of quote; whatever the balance cannot cover is owed
+    /// as debt
+    pub fn charge(&mut self, amount: u64) -> Result<()> {
+        let paid = amount.min(self.collateral);
+        self.collateral -= paid;
+        self.debt = self
+            .debt
+            .checked_add(amount - paid)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+
+    /// Credit a deposit of listed collateral `mint`
+    pub fn deposit_listed(&mut self, mint: Pubkey, amount: u64) -> Result<()> {
+        let balance = match self.collateral_balances.iter().position(|b| b.mint == mint) {
+            Some(idx) => &mut self.collateral_balances[idx],
+            None => {
+                require!(
+                    self.collateral_balances.len() < MAX_COLLATERALS,
+                    ErrorCode::TooManyCollaterals
+                );
+                self.collateral_balances
+                    .push(CollateralBalance { mint, amount: 0 });
+                self.collateral_balances.last_mut().unwrap()
+            }
+        };
+        balance.amount = balance
+            .amount
+            .checked_add(amount)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+
+    /// Debit a withdrawal of listed collateral `mint`, dropping the balance
+    /// once it is empty
+    pub fn withdraw_listed(&mut self, mint: &Pubkey, amount: u64) -> Result<()> {
+        let idx = self
+            .collateral_balances
+            .iter()
+            .position(|b| b.mint == *mint)
+            .ok_or(error!(ErrorCode::InsufficientCollateral))?;
+        let balance = &mut self.collateral_balances[idx];
+        balance.amount = balance
+            .amount
+            .checked_sub(amount)
+            .ok_or(error!(ErrorCode::InsufficientCollateral))?;
+        if balance.amount == 0 {
+            self.collateral_balances.remove(idx);
+        }
+        Ok(())
+    }
+
+    /// Listed collateral held in `mint`
+    pub fn listed_balance(&self, mint: &Pubkey) -> u64 {
+        self.collateral_balances
+            .iter()
+            .find(|b| b.mint == *mint)
+            .map_or(0, |b| b.amount)
+    }
+
+    /// Reserve `qty` of the hedge leg on `side` of `market` for a resting
+    /// order closing it. The other leg's slot is opened too, so a fill
+    /// outgrowing a liquidated leg always has somewhere to spill.
+    pub fn reserve_close(&mut self, market: &Pubkey, side: Side, qty: u64) -> Result<()> {
+        let idx = self
+            .find_position(market, side)
+            .ok_or(error!(ErrorCode::ReduceOnlyExceeded))?;
+        self.find_or_open_position(*market, side.opposite())?;
+        let pos = &mut self.positions[idx];
+        pos.reserved_close_qty = pos
+            .reserved_close_qty
+            .checked_add(qty)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        Ok(())
+    }
+
+    /// Release size reserved for a closing order once it fills or is cancelled
+    pub fn release_close(&mut self, market: &Pubkey, side: Side, qty: u64) {
+        if let Some(idx) = self.find_position(market, side) {
+            let pos = &mut self.positions[idx];
+            pos.reserved_close_qty = pos.reserved_close_qty.saturating_sub(qty);
+        }
+    }
+
+    /// Release what a resting order of `qty` at `price` on `side` held once
+    /// it fills or is cancelled: margin for an opening order, size for a
+    /// closing one
+    pub fn release_resting(
+        &mut self,
+        market: &Pubkey,
+        side: Side,
+        price: u64,
+        qty: u64,
+        leg: Option<PositionLeg>,
+    ) {
+        match leg {
+            Some(leg) if leg.is_close() => self.release_close(market, leg.position_side(), qty),
+            _ => self.release_order(market, side, price as u128 * qty as u128),
+        }
+    }
+
+    /// Track an order resting under `key` on `side` of `market`
+    pub fn add_open_order(
+        &mut self,
+        market: Pubkey,
+        side: Side,
+        key: u128,
+        client_order_id: u64,
+    ) -> Result<()> {
+        require!(
+            self.open_orders.len() < MAX_OPEN_ORDERS,
+            ErrorCode::TooManyOpenOrders
+        );
+        self.open_orders.push(OpenOrder {
+            market,
+            side,
+            key,
+            client_order_id,
+        });
+        Ok(())
+    }
+
+    /// Stop tracking the order under `key` once it left the book
+    pub fn remove_open_order(&mut self, market: &Pubkey, side: Side, key: u128) {
+        if let Some(idx) = self
+            .open_orders
+            .iter()
+            .position(|o| o.market == *market && o.side == side && o.key == key)
+        {
+            self.open_orders.swap_remove(idx);
+        }
+    }
+
+    /// No open size and nothing reserved for resting orders on any market
+    pub fn is_flat(&self) -> bool {
+        self.positions.iter().all(Position::is_flat)
+    }
+
+    /// Markets the account has open size or resting orders on
+    pub fn active_markets(&self) -> Vec<Pubkey> {
+        let mut markets: Vec<Pubkey> = Vec::new();
+        for p in &self.positions {
+            if !p.is_flat() && !markets.contains(&p.market) {
+                markets.push(p.market);
+            }
+        }
+        markets
+    }
+
+    /// Position on `market`, or its leg on `side` in hedge mode
+    pub fn find_position(&self, market: &Pubkey, side: Side) -> Option<usize> {
+        let hedge = self.position_mode == PositionMode::Hedge;
+        self.positions
+            .iter()
+            .position(|p| p.market == *market && (!hedge || p.side == side))
+    }
+
+    /// Position on `market`, or its leg on `side` in hedge mode, opening a
+    /// flat one if the account has none yet
+    pub fn find_or_open_position(&mut self, market: Pubkey, side: Side) -> Result<usize> {
+        if let Some(idx) = self.find_position(&market, side) {
+            return Ok(idx);
+        }
+        require!(
+            self.positions.len() < MAX_POSITIONS,
+            ErrorCode::TooManyPositions
+        );
+        self.positions.push(Position {
+            market,
+            qty: 0,
+            entry_price: 0,
+            side,
+            collateral: 0,
+            realized_pnl: 0,
+            open_bid_notional: 0,
+            open_ask_notional: 0,
+            reserved_close_qty: 0,
+            last_funding: 0,
+        });
+        Ok(self.positions.len() - 1)
+    }
+
+    /// Net a fill into this account's position on `market` and credit the
+    /// realized PnL less `fee` to collateral, or to the position's own
+    /// collateral in isolated mode, where losses beyond it fall on the
+    /// account. In hedge mode the fill goes to the leg of the order; a
+    /// closing fill larger than its leg, which a liquidation can leave
+    /// behind a resting close order, opens the other leg with the excess.
+    pub fn apply_fill(
+        &mut self,
+        market: Pubkey,
+        side: Side,
+        price: u64,
+        qty: u64,
+        leg: Option<PositionLeg>,
+        fee: u64,
+    ) -> Result<i64> {
+        let pos_side = match (self.position_mode, leg) {
+            (PositionMode::Hedge, Some(leg)) => {
+                if leg.is_close() {
+                    let held = self
+                        .find_position(&market, leg.position_side())
+                        .map_or(0, |idx| self.positions[idx].qty);
+                    if qty > held {
+                        let open = Some(PositionLeg::opening(side));
+                        if held == 0 {
+                            return self.apply_fill(market, side, price, qty, open, fee);
+                        }
+                        self.apply_fill(market, side, price, qty - held, open, 0)?;
+                        return self.apply_fill(market, side, price, held, Some(leg), fee);
+                    }
+                }
+                leg.position_side()
+            }
+            _ => side,
+        };
+        if qty == 0 {
+            return Ok(0);
+        }
+        let idx = self.find_or_open_position(market, pos_side)?;
+        let pnl = self.positions[idx].apply_fill(side, price, qty)?;
+        let net = pnl
+            .checked_sub_unsigned(fee)
+            .ok_or(error!(ErrorCode::Overflow))?;
+        self.settle_pnl(idx, net)?;
+        Ok(pnl)
+    }
+
+    /// Settle funding at `mark_price` on every position on `market` like
+    /// fill PnL. Returns the net payment, positive when received.
+    pub fn settle_funding(&mut self, market: &Pubkey, mark_price: i128) -> Result<i64> {
+        let mut total: i64 = 0;
+        for idx in 0..self.positions.len() {
+            if self.positions[idx].market != *market {
+                continue;
+            }
+            let payment = self.positions[idx].funding_payment(mark_price)?;
+            self.settle_pnl(idx, payment)?;
+            total = total
+                .checked_add(payment)
+                .ok_or(error!(ErrorCode::Overflow))?;
+        }
+        Ok(total)
+    }
+
+    /// Book `net` PnL of the position at `idx`: against the account balance
+    /// in cross mode, against the position's collateral in isolated mode
+    /// with any shortfall charged to the account
+    fn settle_pnl(&mut self, idx: usize, net: i64) -> Result<()> {
+        match self.margin_type {
+            MarginType::Cross if net >= 0 => self.credit(net as u64)?,
+            MarginType::Cross => self.charge(net.unsigned_abs())?,
+            MarginType::Isolated => {
+                // isolated PnL and fees stay with the position until it is
+                // closed
+                let pos = &mut self.positions[idx];
+                let shortfall = if net >= 0 {
+                    pos.collateral = pos
+                        .collateral
+                        .checked_add(net as u64)
+                        .ok_or(error!(ErrorCode::Overflow))?;
+                    0
+                } else {
+                    let loss = net.unsigned_abs();
+                    let paid = loss.min(pos.collateral);
+                    pos.collateral -= paid;
+                    loss - paid
+                };
+                let released = if pos.is_flat() {
+                    std::mem::take(&mut pos.collateral)
+                } else {
+                    0
+                };
+                self.charge(shortfall)?;
+                self.credit(released)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop every position, returning isolated collateral to the account.
+    /// Only meaningful while the account is flat.
+    pub fn reset_positions(&mut self) -> Result<()> {
+        for pos in std::mem::take(&mut self.positions) {
+            self.credit(pos.collateral)?;
+        }
+        Ok(())
+    }
+}
+
+/// A resting order of a margin account, located on its book by `key`
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Clone)]
+pub struct OpenOrder {
+    pub market: Pubkey,
+    pub side: Side,
     pub key: u128,
+    pub client_order_id: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Clone)]
+pub struct CollateralBalance {
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, InitSpace, Clone)]
+pub struct Position {
+    pub market: Pubkey,
+    /// Net size; zero once the position is flat
     pub qty: u64,
+    /// Size-weighted average price of the open size
     pub entry_price: u64,
     pub side: Side,
     pub collateral: u64,
+    /// PnL realized on reductions since the position was opened
+    pub realized_pnl: i64,
+    /// Notional of resting bids, reserved until they fill or are cancelled
+    pub open_bid_notional: u64,
+    /// Notional of resting asks, reserved until they fill or are cancelled
+    pub open_ask_notional: u64,
+    /// Size of resting orders closing this hedge leg, which further closing
+    /// orders cannot claim again
+    pub reserved_close_qty: u64,
+    /// When funding was last settled on this position
+    pub last_funding: i64,
+}
+
+impl Position {
+    /// No size and nothing reserved for resting orders
+    pub fn is_flat(&self) -> bool {
+        self.qty == 0
+            && self.open_bid_notional == 0
+            && self.open_ask_notional == 0
+            && self.reserved_close_qty == 0
+    }
+
+    fn open_notional_mut(&mut self, side: Side) -> &mut u64 {
+        match side {
+            Side::Bid => &mut self.open_bid_notional,
+            Side::Ask => &mut self.open_ask_notional,
+        }
+    }
+
+    /// Funding due at `mark_price`, positive when received: longs pay and
+    /// shorts receive the mark's premium over the entry, scaled by size
+    pub fn funding_payment(&self, mark_price: i128) -> Result<i64> {
+        if self.qty == 0 || self.entry_price == 0 {
+            return Ok(0);
+        }
+        let fund = mark_price
+            .checked_sub(self.entry_price as i128)
+            .and_then(|diff| diff.checked_mul(self.qty as i128))
+            .ok_or(error!(ErrorCode::Overflow))?
+            / self.entry_price as i128;
+        let payment = match self.side {
+            Side::Bid => -fund,
+            Side::Ask => fund,
+        };
+        i64::try_from(payment).map_err(|_| error!(ErrorCode::Overflow))
+    }
+
+    /// Net a fill of `qty` at `price` on `side` into the position: fills on
+    /// the position's side move the average entry, fills against it realize
+    /// PnL on the closed size and flip the position once it goes through
+    /// zero. Returns the realized PnL.
+    pub fn apply_fill(&mut self, side: Side, price: u64, qty: u64) -> Result<i64> {
+        if self.qty == 0 {
+            self.side = side;
+            self.entry_price = price;
+            self.qty = qty;
+            return Ok(0);
+        }
+        if self.side == side {
+            let old_notional = (self.entry_price as u128)
+                .checked_mul(self.qty as u128)
+                .ok_or(error!(ErrorCode::Overflow))?;
+            let fill_notional = (price as u128)
+                .checked_mul(qty as u128)
+                .ok_or(error!(ErrorCode::Overflow))?;
+            let new_qty = self
+                .qty
+                .checked_add(qty)
+                .ok_or(error!(ErrorCode::Overflow))?;
+            self.entry_price = old_notional
+                .checked_add(fill_notional)
+                .ok_or(error!(ErrorCode::Overflow))?
+                .checked_div(new_qty as u128)
+                .and_then(|p| u64::try_from(p).ok())
+                .ok_or(error!(ErrorCode::Overflow))?;
+            self.qty = new_qty;
+            return Ok(0);
+        }
+
+        let closed = self.qty.min(qty);
+        let diff = (price as i128) - (self.entry_price as i128);
+        let per_unit = match self.side {
+            Side::Bid => diff,
+            Side::Ask => -diff,
+        };
+        let pnl: i64 = per_unit
+            .checked_mul(closed as i128)
+            .and_then(|p| i64::try_from(p).ok())
+            .ok_or(error!(ErrorCode::Overflow))?;
+        self.realized_pnl = self
+            .realized_pnl
+            .checked_add(pnl)
+            .ok_or(error!(ErrorCode::Overflow))?;
+
+        self.qty -= closed;
+        let flipped = qty - closed;
+        if flipped > 0 {
+            self.side = side;
+            self.entry_price = price;
+            self.qty = flipped;
+        } else if self.qty == 0 {
+            self.entry_price = 0;
+        }
+        Ok(pnl)
+    }
 }
 
 // Governance State
@@ -111,3 +905,344 @@ pub struct Proposal {
     pub votes_against: u64,
     pub executed: bool,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn config(decimals: u8, quote_decimals: u8, weight_bps: u16) -> CollateralConfig {
+        CollateralConfig {
+            mint: Pubkey::new_unique(),
+            vault: Pubkey::new_unique(),
+            oracle_pyth: Pubkey::new_unique(),
+            oracle_switchboard: Pubkey::new_unique(),
+            decimals,
+            quote_decimals,
+            weight_bps,
+            deposit_cap: u64::MAX,
+            total_deposits: 0,
+            oracle_max_age: 60,
+            bump: 0,
+        }
+    }
+
+    pub(crate) fn account(margin_type: MarginType, collateral: u64) -> MarginAccount {
+        MarginAccount {
+            owner: Pubkey::new_unique(),
+            delegate: None,
+            collateral,
+            debt: 0,
+            collateral_balances: Vec::new(),
+            margin_type,
+            position_mode: PositionMode::OneWay,
+            positions: Vec::new(),
+            open_orders: Vec::new(),
+            bump: 0,
+        }
+    }
+
+    #[test]
+    fn charge_beyond_balance_becomes_debt() {
+        let mut m = account(MarginType::Cross, 100);
+        m.charge(150).unwrap();
+        assert_eq!((m.collateral, m.debt), (0, 50));
+        m.credit(80).unwrap();
+        assert_eq!((m.collateral, m.debt), (30, 0));
+    }
+
+    #[test]
+    fn cross_loss_beyond_balance_is_owed() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Cross, 100);
+        m.apply_fill(market, Side::Bid, 1_000, 1, None, 0).unwrap();
+        let pnl = m.apply_fill(market, Side::Ask, 700, 1, None, 0).unwrap();
+        assert_eq!(pnl, -300);
+        assert_eq!((m.collateral, m.debt), (0, 200));
+    }
+
+    #[test]
+    fn isolated_loss_beyond_position_collateral_falls_on_account() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Isolated, 50);
+        let idx = m.find_or_open_position(market, Side::Bid).unwrap();
+        m.positions[idx].collateral = 100;
+        m.apply_fill(market, Side::Bid, 1_000, 1, None, 0).unwrap();
+        m.apply_fill(market, Side::Ask, 800, 1, None, 0).unwrap();
+        assert_eq!((m.collateral, m.debt), (0, 50));
+        assert_eq!(m.positions[idx].collateral, 0);
+    }
+
+    #[test]
+    fn isolated_fee_is_paid_from_position_collateral() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Isolated, 50);
+        let idx = m.find_or_open_position(market, Side::Bid).unwrap();
+        m.positions[idx].collateral = 100;
+        m.apply_fill(market, Side::Bid, 1_000, 2, None, 0).unwrap();
+        m.apply_fill(market, Side::Ask, 990, 1, None, 5).unwrap();
+        assert_eq!(m.positions[idx].collateral, 85);
+        assert_eq!((m.collateral, m.debt), (50, 0));
+    }
+
+    #[test]
+    fn close_fill_past_the_leg_opens_the_other_leg() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Cross, 1_000);
+        m.position_mode = PositionMode::Hedge;
+        m.apply_fill(market, Side::Bid, 100, 2, Some(PositionLeg::OpenLong), 0)
+            .unwrap();
+        m.apply_fill(market, Side::Ask, 110, 3, Some(PositionLeg::CloseLong), 0)
+            .unwrap();
+        let long = m.find_position(&market, Side::Bid).unwrap();
+        let short = m.find_position(&market, Side::Ask).unwrap();
+        assert_eq!(m.positions[long].qty, 0);
+        assert_eq!(
+            (m.positions[short].side, m.positions[short].qty),
+            (Side::Ask, 1)
+        );
+        assert_eq!(m.collateral, 1_020);
+    }
+
+    #[test]
+    fn close_order_keeps_a_slot_for_the_spill() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Cross, 1_000);
+        m.position_mode = PositionMode::Hedge;
+        m.apply_fill(market, Side::Bid, 100, 2, Some(PositionLeg::OpenLong), 0)
+            .unwrap();
+        m.reserve_close(&market, Side::Bid, 2).unwrap();
+        while m.positions.len() < MAX_POSITIONS {
+            m.find_or_open_position(Pubkey::new_unique(), Side::Bid)
+                .unwrap();
+        }
+        // the leg is closed underneath the resting order, whose fill then
+        // spills entirely into the short leg
+        m.apply_fill(market, Side::Ask, 100, 2, Some(PositionLeg::CloseLong), 0)
+            .unwrap();
+        m.apply_fill(market, Side::Ask, 100, 2, Some(PositionLeg::CloseLong), 0)
+            .unwrap();
+        let short = m.find_position(&market, Side::Ask).unwrap();
+        assert_eq!(m.positions[short].qty, 2);
+    }
+
+    #[test]
+    fn weighted_value_scales_by_oracle_exponent() {
+        // 2 SOL (9 decimals) at $150.25 with a 1e-8 feed, 6 decimal quote, 80%
+        let sol = config(9, 6, 8_000);
+        let value = sol
+            .weighted_value(2_000_000_000, 15_025_000_000, -8)
+            .unwrap();
+        assert_eq!(value, 240_400_000);
+    }
+
+    #[test]
+    fn weighted_value_handles_positive_shift() {
+        // 1 whole token of a 0 decimal mint at 3 quote with a 1e0 feed
+        let token = config(0, 6, 10_000);
+        assert_eq!(token.weighted_value(1, 3, 0).unwrap(), 3_000_000);
+    }
+
+    #[test]
+    fn amount_for_value_adds_the_bonus() {
+        // $100 of debt against SOL at $150 with a 1e-8 feed and a 5% bonus
+        let sol = config(9, 6, 8_000);
+        let amount = sol
+            .amount_for_value(100_000_000, 15_000_000_000, -8, 500)
+            .unwrap();
+        assert_eq!(amount, 700_000_000);
+    }
+
+    #[test]
+    fn weighted_value_ignores_non_positive_prices() {
+        let token = config(6, 6, 10_000);
+        assert_eq!(token.weighted_value(1_000_000, -1, -8).unwrap(), 0);
+    }
+
+    #[test]
+    fn open_orders_are_capped_and_removed_by_key() {
+        let market = Pubkey::new_unique();
+        let mut m = account(MarginType::Cross, 0);
+        for key in 0..MAX_OPEN_ORDERS as u128 {
+            m.add_open_order(market, Side::Bid, key, 0).unwrap();
+        }
+        assert!(m.add_open_order(market, Side::Bid, 99, 0).is_err());
+        // same key on the other side is a different order
+        m.remove_open_order(&market, Side::Ask, 3);
+        assert_eq!(m.open_orders.len(), MAX_OPEN_ORDERS);
+        m.remove_open_order(&market, Side::Bid, 3);
+        assert!(m.open_orders.iter().all(|o| o.key != 3));
+        m.add_open_order(market, Side::Bid, 99, 0).unwrap();
+    }
+
+    pub(crate) fn params(brackets: &[(u64, u16, u16)]) -> MarketParams {
+        MarketParams {
+            tick_size: 1,
+            lot_size: 1,
+            initial_margin_bps: 500,
+            funding_interval: 3600,
+            maintenance_margin_bps: 250,
+            margin_brackets: brackets
+                .iter()
+                .map(|&(min_notional, initial, maintenance)| MarginBracket {
+                    min_notional,
+                    initial_margin_bps: initial,
+                    maintenance_margin_bps: maintenance,
+                })
+                .collect(),
+            maker_fee_bps: 0,
+            taker_fee_bps: 0,
+        }
+    }
+
+    pub(crate) fn position() -> Position {
+        Position {
+            market: Pubkey::new_unique(),
+            qty: 0,
+            entry_price: 0,
+            side: Side::Bid,
+            collateral: 0,
+            realized_pnl: 0,
+            open_bid_notional: 0,
+            open_ask_notional: 0,
+            reserved_close_qty: 0,
+            last_funding: 0,
+        }
+    }
+
+    #[test]
+    fn margin_ratios_pick_highest_reached_bracket() {
+        let p = params(&[(1_000, 1_000, 500), (10_000, 2_000, 1_000)]);
+        p.validate().unwrap();
+        assert_eq!(p.margin_ratios(999), (500, 250));
+        assert_eq!(p.margin_ratios(1_000), (1_000, 500));
+        assert_eq!(p.margin_ratios(9_999), (1_000, 500));
+        assert_eq!(p.margin_ratios(50_000), (2_000, 1_000));
+        assert_eq!(p.initial_margin(50_000), 10_000);
+        assert_eq!(p.maintenance_margin(1_001), 51);
+    }
+
+    #[test]
+    fn brackets_must_tighten_with_size() {
+        assert!(params(&[(1_000, 400, 200)]).validate().is_err());
+        assert!(params(&[(1_000, 1_000, 500), (1_000, 2_000, 1_000)])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn same_side_fills_average_the_entry() {
+        let mut pos = position();
+        assert_eq!(pos.apply_fill(Side::Bid, 100, 10).unwrap(), 0);
+        assert_eq!(pos.apply_fill(Side::Bid, 130, 20).unwrap(), 0);
+        assert_eq!((pos.side, pos.qty, pos.entry_price), (Side::Bid, 30, 120));
+    }
+
+    #[test]
+    fn opposite_fills_realize_pnl_and_flip_through_zero() {
+        let mut pos = position();
+        pos.apply_fill(Side::Ask, 200, 10).unwrap();
+        // short 10 @ 200, buy 4 @ 150: +50 on each closed unit
+        assert_eq!(pos.apply_fill(Side::Bid, 150, 4).unwrap(), 200);
+        assert_eq!((pos.side, pos.qty, pos.entry_price), (Side::Ask, 6, 200));
+        // buy 10 @ 210 closes 6 at a loss and opens long 4 @ 210
+        assert_eq!(pos.apply_fill(Side::Bid, 210, 10).unwrap(), -60);
+        assert_eq!((pos.side, pos.qty, pos.entry_price), (Side::Bid, 4, 210));
+        assert_eq!(pos.realized_pnl, 140);
+        // an exact close leaves the position flat
+        assert_eq!(pos.apply_fill(Side::Ask, 200, 4).unwrap(), -40);
+        assert_eq!((pos.qty, pos.entry_price), (0, 0));
+    }
+
+    pub(crate) fn market(base_decimals: u8, quote_decimals: u8) -> Market {
+        Market {
+            authority: Pubkey::default(),
+            base_mint: Pubkey::default(),
+            quote_mint: Pubkey::default(),
+            base_decimals,
+            quote_decimals,
+            oracle_pyth: Pubkey::new_unique(),
+            oracle_switchboard: Pubkey::new_unique(),
+            event_queue: Pubkey::default(),
+            params: params(&[]),
+            nonce: 0,
+            last_funding_timestamp: 0,
+            cumulative_funding_rate: 0,
+            fees_accrued: 0,
+            halted: false,
+        }
+    }
+
+    #[test]
+    fn book_price_rescales_oracle_price_to_native_units() {
+        // 150.00000000 quote per whole base token at expo -8
+        let price = 15_000_000_000;
+        // 6-decimal quote, 0-decimal base: 150 * 10^6 native quote per unit
+        assert_eq!(market(0, 6).book_price(price, -8).unwrap(), 150_000_000);
+        // 6-decimal quote, 3-decimal base: 150 * 10^3
+        assert_eq!(market(3, 6).book_price(price, -8).unwrap(), 150_000);
+        // same decimals: the whole-token price itself
+        assert_eq!(market(6, 6).book_price(price, -8).unwrap(), 150);
+    }
+
+    /// Account holding `qty` on `side` at an entry of 100, with `collateral`
+    /// on the position in isolated mode
+    fn funded(
+        margin_type: MarginType,
+        side: Side,
+        qty: u64,
+        collateral: u64,
+    ) -> (MarginAccount, Pubkey) {
+        let market = Pubkey::new_unique();
+        let mut m = account(margin_type, 0);
+        m.apply_fill(market, side, 100, qty, None, 0).unwrap();
+        match margin_type {
+            MarginType::Cross => m.collateral = collateral,
+            MarginType::Isolated => m.positions[0].collateral = collateral,
+        }
+        (m, market)
+    }
+
+    #[test]
+    fn isolated_funding_moves_position_collateral_both_ways() {
+        // long, mark below entry: shorts pay the long 10 * 100 / 100
+        let (mut m, market) = funded(MarginType::Isolated, Side::Bid, 100, 50);
+        assert_eq!(m.settle_funding(&market, 90).unwrap(), 10);
+        assert_eq!(
+            (m.positions[0].collateral, m.collateral, m.debt),
+            (60, 0, 0)
+        );
+        // long, mark above entry: the long pays
+        let (mut m, market) = funded(MarginType::Isolated, Side::Bid, 100, 50);
+        assert_eq!(m.settle_funding(&market, 120).unwrap(), -20);
+        assert_eq!(
+            (m.positions[0].collateral, m.collateral, m.debt),
+            (30, 0, 0)
+        );
+        // short, mark above entry: the short receives
+        let (mut m, market) = funded(MarginType::Isolated, Side::Ask, 100, 50);
+        assert_eq!(m.settle_funding(&market, 120).unwrap(), 20);
+        assert_eq!(m.positions[0].collateral, 70);
+        // short, mark below entry: a payment beyond the position's
+        // collateral falls on the account
+        let (mut m, market) = funded(MarginType::Isolated, Side::Ask, 100, 50);
+        m.collateral = 5;
+        assert_eq!(m.settle_funding(&market, 40).unwrap(), -60);
+        assert_eq!((m.positions[0].collateral, m.collateral, m.debt), (0, 0, 5));
+    }
+
+    #[test]
+    fn cross_funding_goes_through_charge_and_credit() {
+        let (mut m, market) = funded(MarginType::Cross, Side::Bid, 100, 15);
+        assert_eq!(m.settle_funding(&market, 120).unwrap(), -20);
+        assert_eq!((m.collateral, m.debt), (0, 5));
+        // receipts repay the debt before adding to the balance
+        assert_eq!(m.settle_funding(&market, 92).unwrap(), 8);
+        assert_eq!((m.collateral, m.debt), (3, 0));
+        let (mut m, market) = funded(MarginType::Cross, Side::Ask, 100, 15);
+        assert_eq!(m.settle_funding(&market, 110).unwrap(), 10);
+        assert_eq!(m.settle_funding(&market, 70).unwrap(), -30);
+        assert_eq!((m.collateral, m.debt), (0, 5));
+        // positions on other markets are untouched
+        assert_eq!(m.settle_funding(&Pubkey::new_unique(), 70).unwrap(), 0);
+    }
+}
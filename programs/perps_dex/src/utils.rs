@@ -1,18 +1,27 @@
 use crate::errors::ErrorCode;
-use crate::instructions::{InitializeEventQueue, UpdateRiskParams};
+use crate::event_queue::EventQueueView;
+use crate::instructions::{CloseEventQueue, HaltMarket, InitializeEventQueue, UpdateRiskParams};
 
-use crate::state::{EventQueue, MarketParams, OrderEvent};
+use crate::slab::Slab;
+use crate::state::{Market, MarketParams, OrderbookSide};
 use anchor_lang::prelude::*;
-use anchor_lang::AnchorSerialize;
+use anchor_lang::ZeroCopy;
+use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
+use bytemuck::Pod;
 use pyth_sdk_solana::state::SolanaPriceAccount;
+use std::cell::RefMut;
+use std::mem::{align_of, size_of};
 use switchboard_on_demand::PullFeedAccountData;
 
 const MAX_DEVIATION_BPS: i128 = 50;
 
+/// Switchboard price of `feed_account` rescaled to `10^expo` units, the
+/// exponent the Pyth feed it is compared with reports in
 pub fn get_switchboard_price(
     feed_account: &AccountInfo,
     max_stale_slots: u64,
     min_samples: u32,
+    expo: i32,
 ) -> Result<i128> {
     let account_data = feed_account.data.borrow();
     let feed = PullFeedAccountData::parse(account_data)
@@ -20,20 +29,30 @@ pub fn get_switchboard_price(
     let price = feed
         .get_value(&Clock::get()?, max_stale_slots, min_samples, true)
         .map_err(|_| error!(ErrorCode::InvalidPriceFeed))?;
-    let price = price
-        .mantissa()
-        .checked_mul(10i128.pow(price.scale() as u32))
+    // the decimal is mantissa * 10^-scale
+    let shift = -(price.scale() as i64) - expo as i64;
+    let factor = 10i128
+        .checked_pow(shift.unsigned_abs() as u32)
         .ok_or_else(|| error!(ErrorCode::InvalidPriceFeed))?;
+    let price = if shift >= 0 {
+        price.mantissa().checked_mul(factor)
+    } else {
+        price.mantissa().checked_div(factor)
+    }
+    .ok_or_else(|| error!(ErrorCode::InvalidPriceFeed))?;
     Ok(price)
 }
 
-pub fn get_mark_price(
+/// Oracle price as `(price, expo)`, worth `price * 10^expo` quote per whole
+/// base token. The Pyth price sets the exponent; the Switchboard price is
+/// averaged in when the two agree.
+pub fn get_oracle_price(
     pyth_account: &mut AccountInfo,
     switchboard_account: &mut AccountInfo,
     max_age: u64,
     max_stale_slots: u64,
     min_samples: u32,
-) -> Result<i128> {
+) -> Result<(i128, i32)> {
     let pyth_feed = SolanaPriceAccount::account_info_to_feed(pyth_account)
         .map_err(|_| error!(ErrorCode::InvalidPriceFeed))?;
     let clock = Clock::get()?;
@@ -42,7 +61,12 @@ pub fn get_mark_price(
         .ok_or(error!(ErrorCode::StalePrice))?;
     let pyth_price = pyth_data.price as i128;
 
-    let sb_price = get_switchboard_price(&switchboard_account, max_stale_slots, min_samples)?;
+    let sb_price = get_switchboard_price(
+        switchboard_account,
+        max_stale_slots,
+        min_samples,
+        pyth_data.expo,
+    )?;
 
     let diff = (pyth_price - sb_price).abs();
     let deviation = if pyth_price != 0 {
@@ -52,50 +76,178 @@ pub fn get_mark_price(
     };
 
     if deviation <= MAX_DEVIATION_BPS {
-        Ok((pyth_price + sb_price) / 2)
+        Ok(((pyth_price + sb_price) / 2, pyth_data.expo))
     } else {
-        Ok(pyth_price)
+        Ok((pyth_price, pyth_data.expo))
+    }
+}
+
+/// Mark price of `market` from its oracles, no older than its funding
+/// interval, in book price units
+pub fn get_mark_price(
+    market: &Market,
+    pyth_account: &mut AccountInfo,
+    switchboard_account: &mut AccountInfo,
+    max_stale_slots: u64,
+    min_samples: u32,
+) -> Result<i128> {
+    let (price, expo) = get_oracle_price(
+        pyth_account,
+        switchboard_account,
+        market.params.funding_interval,
+        max_stale_slots,
+        min_samples,
+    )?;
+    market.book_price(price, expo)
+}
+
+/// Mark price from oracle accounts that an instruction only needs in some
+/// cases; `None` unless both are passed
+pub fn get_optional_mark_price(
+    market: &Market,
+    pyth_account: Option<&AccountInfo>,
+    switchboard_account: Option<&AccountInfo>,
+) -> Result<Option<i128>> {
+    match (pyth_account, switchboard_account) {
+        (Some(pyth), Some(sb)) => Ok(Some(get_mark_price(
+            market,
+            &mut pyth.clone(),
+            &mut sb.clone(),
+            5,
+            3,
+        )?)),
+        _ => Ok(None),
     }
 }
 
-pub fn push_event(
-    queue: &mut Account<EventQueue>,
-    event_type: u8,
-    key: u128,
-    price: u64,
-    qty: u64,
-    owner: Pubkey,
+/// Move collateral with `transfer_checked`, which both the token program
+/// and Token-2022 accept. `signer_seeds` is empty when `authority` signs
+/// the transaction itself.
+pub fn transfer_collateral<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    authority: AccountInfo<'info>,
+    mint: &InterfaceAccount<'info, Mint>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
 ) -> Result<()> {
-    let event = OrderEvent {
-        event_type,
-        key,
-        price,
-        qty,
-        owner,
+    let cpi_accounts = TransferChecked {
+        from: from.to_account_info(),
+        mint: mint.to_account_info(),
+        to: to.to_account_info(),
+        authority,
     };
-    let data = event
-        .try_to_vec()
-        .map_err(|_| error!(ErrorCode::EventSerializationFailure))?;
-    queue.events.extend_from_slice(&data);
-    queue.tail = queue.tail.wrapping_add(1);
-    if queue.tail == queue.head {
-        queue.head = queue.head.wrapping_add(1);
+    let cpi_ctx =
+        CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer_seeds);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+/// Borrow a zero-copy account as its `H` header followed by as many `T` as
+/// the rest of the data holds, so the capacity follows the account size.
+/// Runs the checks of `load_init` when `init` is set and of `load_mut`
+/// otherwise.
+pub fn load_with_tail_mut<'a, H: ZeroCopy + Owner, T: Pod>(
+    loader: &'a AccountLoader<'_, H>,
+    init: bool,
+) -> Result<(RefMut<'a, H>, RefMut<'a, [T]>)> {
+    if init {
+        drop(loader.load_init()?);
+    } else {
+        drop(loader.load_mut()?);
     }
+    let data = loader.as_ref().try_borrow_mut_data()?;
+    let start = 8 + size_of::<H>();
+    let len = data
+        .len()
+        .checked_sub(start)
+        .ok_or(error!(ErrorCode::InvalidAccountLength))?
+        / size_of::<T>()
+        * size_of::<T>();
+    require!(
+        data[start..].as_ptr().align_offset(align_of::<T>()) == 0,
+        ErrorCode::InvalidAccountLength
+    );
+    Ok(RefMut::map_split(data, |data| {
+        let (header, tail) = data.split_at_mut(start);
+        (
+            bytemuck::from_bytes_mut(&mut header[8..]),
+            bytemuck::cast_slice_mut(&mut tail[..len]),
+        )
+    }))
+}
+
+/// Amount that actually arrived in `vault` since it held `before`, which is
+/// less than the amount sent for mints with a transfer fee
+pub fn received_amount(vault: &mut InterfaceAccount<TokenAccount>, before: u64) -> Result<u64> {
+    vault.reload()?;
+    vault
+        .amount
+        .checked_sub(before)
+        .ok_or(error!(ErrorCode::InvalidAmount))
+}
+
+/// Stop the market from accepting new orders. Resting orders can still be
+/// cancelled and filled events consumed; once both books and the event
+/// queue are drained they may be closed. A halt is permanent.
+pub fn halt_market(ctx: Context<HaltMarket>) -> Result<()> {
+    ctx.accounts.market.halted = true;
     Ok(())
 }
 
+/// Replace the market's risk parameters. Resting orders are kept in ticks
+/// and lots, so the tick and lot size only change while both books are
+/// empty.
 pub fn update_risk_params(ctx: Context<UpdateRiskParams>, new_params: MarketParams) -> Result<()> {
+    new_params.validate()?;
+    let old = &ctx.accounts.market.params;
+    if new_params.tick_size != old.tick_size || new_params.lot_size != old.lot_size {
+        require_empty_book(&ctx.accounts.bids, ctx.accounts.bid_slab.as_ref())?;
+        require_empty_book(&ctx.accounts.asks, ctx.accounts.ask_slab.as_ref())?;
+    }
     let m = &mut ctx.accounts.market;
     m.params = new_params;
     Ok(())
 }
 
-pub fn initialize_event_queue(ctx: Context<InitializeEventQueue>) -> Result<()> {
-    let eq = &mut ctx.accounts.event_queue;
-    eq.market = ctx.accounts.market.key();
-    eq.head = 0;
-    eq.tail = 0;
-    eq.events = Vec::new();
-    eq.bump = ctx.bumps.event_queue;
+/// Check that the book side at `side` has no resting orders. A side that
+/// was never created, or was closed, counts as empty; otherwise its `slab`
+/// must be passed.
+fn require_empty_book(side: &AccountInfo, slab: Option<&AccountLoader<Slab>>) -> Result<()> {
+    if side.data_is_empty() {
+        return Ok(());
+    }
+    let ob = OrderbookSide::try_deserialize(&mut &side.try_borrow_data()?[..])?;
+    let slab = slab
+        .filter(|s| s.key() == ob.slab)
+        .ok_or(error!(ErrorCode::InvalidSlabData))?;
+    require!(slab.load()?.leaf_count == 0, ErrorCode::OrderbookNotEmpty);
+    Ok(())
+}
+
+/// Attach a fresh event queue to a market that has none. Replacing a live
+/// queue would drop its unconsumed fills.
+pub fn initialize_event_queue(ctx: Context<InitializeEventQueue>, capacity: u32) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    require!(
+        market.event_queue == Pubkey::default(),
+        ErrorCode::EventQueueAlreadyInitialized
+    );
+    let mut eq = EventQueueView::load_init(&ctx.accounts.event_queue)?;
+    eq.init(market.key(), capacity as usize)?;
+    market.event_queue = ctx.accounts.event_queue.key();
+    Ok(())
+}
+
+/// Close a market's event queue, returning the rent to the market
+/// authority. Only allowed once the market is halted and every event has
+/// been consumed.
+pub fn close_event_queue(ctx: Context<CloseEventQueue>) -> Result<()> {
+    require!(ctx.accounts.market.halted, ErrorCode::MarketNotHalted);
+    require!(
+        ctx.accounts.event_queue.load()?.is_empty(),
+        ErrorCode::EventQueueNotEmpty
+    );
+    ctx.accounts.market.event_queue = Pubkey::default();
     Ok(())
 }
@@ -131,7 +131,7 @@ describe("High-Frequency Trading Simulation - PDAs and Vault", () => {
     );
 
     [marginPda, marginBump] = await PublicKey.findProgramAddressSync(
-      [Buffer.from("margin"), marketPda.toBuffer(), provider.wallet.publicKey.toBuffer()],
+      [Buffer.from("margin"), provider.wallet.publicKey.toBuffer()],
       program.programId
     );
   });
@@ -232,9 +232,12 @@ describe("High-Frequency Tradin Simulation - Market Initialization", () => {
       .initializeMarket(marketNonce, {
         tickSize: new anchor.BN(1),
         lotSize: new anchor.BN(1),
-        leverageLimit: 20,
+        initialMarginBps: 500,
         fundingInterval: new anchor.BN(3600),
-        maintenanceMarginRatio: 500,
+        maintenanceMarginBps: 500,
+        marginBrackets: [],
+        makerFeeBps: 0,
+        takerFeeBps: 0,
       })
       .accounts({
         market: marketPda,
@@ -251,8 +254,8 @@ describe("High-Frequency Tradin Simulation - Market Initialization", () => {
     expect(marketAccount.quoteMint.toBase58()).to.equal(mint.toBase58());
     expect(marketAccount.params.tickSize.toNumber()).to.equal(1);
     expect(marketAccount.params.lotSize.toNumber()).to.equal(1);
-    expect(marketAccount.params.leverageLimit).to.equal(20);
-    expect(marketAccount.params.maintenanceMarginRatio).to.equal(500);
+    expect(marketAccount.params.initialMarginBps).to.equal(500);
+    expect(marketAccount.params.maintenanceMarginBps).to.equal(500);
   });
 });
 */
@@ -266,11 +269,17 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
   let marketBump: number;
   let orderbookPda: PublicKey;
   let orderbookBump: number;
-  let eqPda: PublicKey;
-  let eqBump: number;
+  let askOrderbookPda: PublicKey;
+  const bidSlab = Keypair.generate();
+  const askSlab = Keypair.generate();
+  // slab accounts hold a 32 byte header and 2 * capacity - 1 nodes of 112 bytes
+  const slabSpace = (capacity: number) => 8 + 32 + (capacity * 2 - 1) * 112;
+  const eventQueueSpace = (capacity: number) => 8 + 56 + capacity * 192;
+  const eventQueue = Keypair.generate();
   let marginPda: PublicKey;
   let marginBump: number;
-  let marketVault: PublicKey;
+  let registryPda: PublicKey;
+  let quoteVault: PublicKey;
   let user: Keypair;
   let userCollateral: PublicKey;
   const marketNonce = 0;
@@ -319,13 +328,15 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
     );
     console.log("Market PDA:", marketPda.toBase58());
 
-    marketVault = await createPdaOwnedTokenAccount(
-      provider.connection,
-      provider.wallet.payer,
-      mint,
-      marketPda
+    [registryPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("collateral_registry")],
+      program.programId
     );
-    console.log("Market vault:", marketVault.toBase58());
+    [quoteVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("quote_vault")],
+      program.programId
+    );
+    console.log("Quote vault:", quoteVault.toBase58());
 
     [orderbookPda, orderbookBump] = await PublicKey.findProgramAddressSync(
       [Buffer.from("orderbook"), marketPda.toBuffer(), Buffer.from([0])],
@@ -333,32 +344,65 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
     );
     console.log("Orderbook PDA:", orderbookPda.toBase58());
 
-    [eqPda, eqBump] = await PublicKey.findProgramAddressSync(
-      [Buffer.from("eventqueue"), marketPda.toBuffer()],
+    [askOrderbookPda] = PublicKey.findProgramAddressSync(
+      [Buffer.from("orderbook"), marketPda.toBuffer(), Buffer.from([1])],
       program.programId
     );
-    console.log("Event queue PDA:", eqPda.toBase58());
+    console.log("Ask orderbook PDA:", askOrderbookPda.toBase58());
+
+    console.log("Event queue:", eventQueue.publicKey.toBase58());
 
     [marginPda, marginBump] = await PublicKey.findProgramAddressSync(
-      [Buffer.from("margin"), marketPda.toBuffer(), user.publicKey.toBuffer()],
+      [Buffer.from("margin"), user.publicKey.toBuffer()],
       program.programId
     );
     console.log("Margin PDA:", marginPda.toBase58());
 
+    // Initialize the collateral registry with the quote mint markets settle in
+    try {
+      await program.methods
+        .initializeCollateralRegistry()
+        .accounts({
+          registry: registryPda,
+          quoteMint: mint,
+          quoteVault: quoteVault,
+          authority: provider.wallet.publicKey,
+          program: program.programId,
+          programData: PublicKey.findProgramAddressSync(
+            [program.programId.toBuffer()],
+            new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111")
+          )[0],
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        } as any)
+        .rpc();
+      console.log("Collateral registry initialized successfully");
+    } catch (err) {
+      console.error("Collateral registry initialization failed:", err);
+      throw err;
+    }
+
     // Initialize market
     try {
       await program.methods
         .initializeMarket(marketNonce, {
           tickSize: new anchor.BN(1),
           lotSize: new anchor.BN(1),
-          leverageLimit: 20,
+          initialMarginBps: 500,
           fundingInterval: new anchor.BN(3600),
-          maintenanceMarginRatio: 500,
+          maintenanceMarginBps: 500,
+          marginBrackets: [],
+          makerFeeBps: 0,
+          takerFeeBps: 0,
         })
         .accounts({
           market: marketPda,
           baseMint: mint,
           quoteMint: mint,
+          registry: registryPda,
+          // no feeds on the local validator; the market only needs their keys
+          oraclePyth: anchor.web3.Keypair.generate().publicKey,
+          oracleSwitchboard: anchor.web3.Keypair.generate().publicKey,
           authority: provider.wallet.publicKey,
           systemProgram: anchor.web3.SystemProgram.programId,
         } as any)
@@ -374,7 +418,6 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
       await program.methods
         .initializeMargin()
         .accounts({
-          market: marketPda,
           margin: marginPda,
           user: user.publicKey,
           systemProgram: anchor.web3.SystemProgram.programId,
@@ -393,10 +436,13 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
         .initializeOrderbook(0, new anchor.BN(10))
         .accounts({
           orderbookSide: orderbookPda,
+          slab: bidSlab.publicKey,
           market: marketPda,
           authority: provider.wallet.publicKey,
           systemProgram: anchor.web3.SystemProgram.programId,
         } as any)
+        .preInstructions([await program.account.slab.createInstruction(bidSlab, slabSpace(10))])
+        .signers([bidSlab])
         .rpc();
       console.log("Orderbook initialized successfully");
     } catch (err) {
@@ -404,16 +450,37 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
       throw err;
     }
 
-    // Initialize event queue
+    // Initialize ask side so limit orders have a book to match against
     try {
       await program.methods
-        .initializeEventQueue()
+        .initializeOrderbook(1, new anchor.BN(10))
         .accounts({
-          eventQueue: eqPda,
+          orderbookSide: askOrderbookPda,
+          slab: askSlab.publicKey,
           market: marketPda,
           authority: provider.wallet.publicKey,
           systemProgram: anchor.web3.SystemProgram.programId,
         } as any)
+        .preInstructions([await program.account.slab.createInstruction(askSlab, slabSpace(10))])
+        .signers([askSlab])
+        .rpc();
+      console.log("Ask orderbook initialized successfully");
+    } catch (err) {
+      console.error("Ask orderbook initialization failed:", err);
+      throw err;
+    }
+
+    // Initialize event queue
+    try {
+      await program.methods
+        .initializeEventQueue(256)
+        .accounts({
+          eventQueue: eventQueue.publicKey,
+          market: marketPda,
+          authority: provider.wallet.publicKey,
+        } as any)
+        .preInstructions([await program.account.eventQueue.createInstruction(eventQueue, eventQueueSpace(256))])
+        .signers([eventQueue])
         .rpc();
       console.log("Event queue initialized successfully");
     } catch (err) {
@@ -439,7 +506,7 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
 
 
 
-    const eventQueueAccount = await program.account.eventQueue.fetch(eqPda);
+    const eventQueueAccount = await program.account.eventQueue.fetch(eventQueue.publicKey);
     expect(eventQueueAccount.market.toBase58()).to.equal(marketPda.toBase58(), "Event queue market should match market PDA");
 
   });
@@ -449,12 +516,12 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
       await program.methods
         .depositCollateral(new anchor.BN(1_000_000))
         .accounts({
-          market: marketPda,
-          authority: provider.wallet.publicKey,
+          registry: registryPda,
           margin: marginPda,
           user: user.publicKey,
+          quoteMint: mint,
           userCollateral: userCollateral,
-          marketVault: marketVault,
+          quoteVault: quoteVault,
           tokenProgram: TOKEN_PROGRAM_ID,
         } as any)
         .signers([user])
@@ -465,8 +532,8 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
       throw err;
     }
 
-    const vaultInfo = await getAccount(provider.connection, marketVault);
-    expect(vaultInfo.amount.toString()).to.equal("1000000", "Market vault should have 1,000,000 tokens");
+    const vaultInfo = await getAccount(provider.connection, quoteVault);
+    expect(vaultInfo.amount.toString()).to.equal("1000000", "Quote vault should have 1,000,000 tokens");
 
     const userCollateralInfo = await getAccount(provider.connection, userCollateral);
     expect(userCollateralInfo.amount.toString()).to.equal("0", "User collateral should be empty after deposit");
@@ -487,15 +554,20 @@ describe("High Frequency Trading Simulation - Account Initialization and Collate
           .placeLimitOrder(
             { bid: {} },
             new anchor.BN(1000 + i),
-            new anchor.BN(10)
+            new anchor.BN(10),
+            { limit: {} },
+            new anchor.BN(i + 1),
+            null
           )
           .accounts({
             orderbookSide: orderbookPda,
-            eventQueue: eqPda,
+            slab: bidSlab.publicKey,
+            oppositeOrderbookSide: askOrderbookPda,
+            oppositeSlab: askSlab.publicKey,
+            eventQueue: eventQueue.publicKey,
             margin: marginPda,
             user: user.publicKey,
             market: marketPda,
-            tokenProgram: TOKEN_PROGRAM_ID,
           } as any)
           .signers([user])
           .instruction()
@@ -552,7 +624,7 @@ describe("High Frequency Trading Simulation - Collateral Deposit", () => {
     );
 
     [marginPda, marginBump] = await PublicKey.findProgramAddressSync(
-      [Buffer.from("margin"), marketPda.toBuffer(), provider.wallet.publicKey.toBuffer()],
+      [Buffer.from("margin"), provider.wallet.publicKey.toBuffer()],
       program.programId
     );
 
@@ -578,9 +650,12 @@ describe("High Frequency Trading Simulation - Collateral Deposit", () => {
       .initializeMarket(marketNonce, {
         tickSize: new anchor.BN(1),
         lotSize: new anchor.BN(1),
-        leverageLimit: 20,
+        initialMarginBps: 500,
         fundingInterval: new anchor.BN(3600),
-        maintenanceMarginRatio: 500,
+        maintenanceMarginBps: 500,
+        marginBrackets: [],
+        makerFeeBps: 0,
+        takerFeeBps: 0,
       })
       .accounts({
         market: marketPda,
@@ -593,7 +668,6 @@ describe("High Frequency Trading Simulation - Collateral Deposit", () => {
     await program.methods
       .initializeMargin()
       .accounts({
-        market: marketPda,
         margin: marginPda,
         user: user.publicKey,
       } as any)
@@ -607,6 +681,7 @@ describe("High Frequency Trading Simulation - Collateral Deposit", () => {
         authority: provider.wallet.publicKey,
         margin: marginPda,
         user: user.publicKey,
+        quoteMint: mint,
         userCollateral: userCollateral,
         marketVault: marketVault,
         tokenProgram: TOKEN_PROGRAM_ID,